@@ -0,0 +1,147 @@
+
+use std::fs;
+use std::env;
+use std::path::{Path, PathBuf};
+use std::error::Error;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use serde::{Serialize, Deserialize};
+use serde::de::DeserializeOwned;
+use sha2::{Digest, Sha256};
+
+pub fn hash(parts: &[&[u8]]) -> String {
+    let mut hasher = Sha256::new();
+    for part in parts {
+        hasher.update((part.len() as u64).to_le_bytes());
+        hasher.update(part);
+    }
+    hasher.finalize().iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn hash_file(path: &Path) -> Option<String> {
+    fs::read(path).ok().map(|content| hash(&[&content]))
+}
+
+/// `$XDG_CACHE_HOME/nix-codemod`, or `~/.cache/nix-codemod`
+pub fn default_cache_dir() -> Option<PathBuf> {
+    env::var_os("XDG_CACHE_HOME").map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| Path::new(&home).join(".cache")))
+        .map(|dir| dir.join("nix-codemod"))
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+struct Entry<T> {
+    /// The files the value was computed from, and their hashes
+    files: Vec<(PathBuf, String)>,
+    value: T,
+}
+
+/// Values computed in earlier runs, stored in a JSON file; only the entries
+/// used during this run are written back.
+pub struct Cache<T> {
+    path: Option<PathBuf>,
+    old: HashMap<String, Entry<T>>,
+    new: Mutex<HashMap<String, Entry<T>>>,
+    hits: AtomicUsize,
+    misses: AtomicUsize,
+    compute_time: AtomicU64,
+}
+
+pub struct CacheStats {
+    pub hits: usize,
+    pub misses: usize,
+    /// Time spent computing the values that weren't in the cache
+    pub compute_time: Duration,
+}
+
+impl<T: Clone + Serialize + DeserializeOwned> Cache<T> {
+    /// A cache backed by `path`, or one that always misses
+    pub fn load(path: Option<PathBuf>) -> Self {
+        let old = path.as_ref()
+            .and_then(|path| fs::read_to_string(path).ok())
+            .and_then(|content| serde_json::from_str(&content).ok())
+            .unwrap_or_default();
+        Cache {
+            path,
+            old,
+            new: Mutex::new(HashMap::new()),
+            hits: AtomicUsize::new(0),
+            misses: AtomicUsize::new(0),
+            compute_time: AtomicU64::new(0),
+        }
+    }
+
+    /// The value for `key` if none of the files it was computed from changed,
+    /// or else what `compute` returns, along with the files it read
+    pub fn get_or_compute(&self, key: String, compute: impl FnOnce() -> (T, Vec<PathBuf>)) -> T {
+        if let Some(entry) = self.old.get(&key) {
+            if entry.files.iter().all(|(path, h)| hash_file(path).as_ref() == Some(h)) {
+                self.hits.fetch_add(1, Ordering::Relaxed);
+                self.new.lock().unwrap_or_else(|e| e.into_inner()).insert(key, entry.clone());
+                return entry.value.clone()
+            }
+        }
+
+        let start = Instant::now();
+        let (value, files) = compute();
+        self.compute_time.fetch_add(start.elapsed().as_nanos() as u64, Ordering::Relaxed);
+        self.misses.fetch_add(1, Ordering::Relaxed);
+
+        if self.path.is_some() {
+            let files = files.into_iter()
+                .filter_map(|path| hash_file(&path).map(|h| (path, h)))
+                .collect();
+            self.new.lock().unwrap_or_else(|e| e.into_inner()).insert(key, Entry { files, value: value.clone() });
+        }
+        value
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            compute_time: Duration::from_nanos(self.compute_time.load(Ordering::Relaxed)),
+        }
+    }
+
+    pub fn save(self) -> Result<(), Box<dyn Error>> {
+        if let Some(path) = self.path {
+            if let Some(dir) = path.parent() {
+                fs::create_dir_all(dir)?;
+            }
+            fs::write(path, serde_json::to_string(&self.new.into_inner().unwrap_or_else(|e| e.into_inner()))?)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod cache_tests {
+    use pretty_assertions::assert_eq;
+
+    use crate::test_dir::TestDir;
+    use super::*;
+
+    #[test]
+    fn test_cache() {
+        let dir = TestDir::new("cache");
+        let module = dir.write("module.nix", "{ }");
+        let cache_path = dir.path().join("cache.json");
+
+        let run = |expected_hits: usize| {
+            let cache: Cache<String> = Cache::load(Some(cache_path.clone()));
+            let value = cache.get_or_compute("key".to_string(), || (fs::read_to_string(&module).unwrap(), vec![module.clone()]));
+            assert_eq!(cache.stats().hits, expected_hits);
+            cache.save().unwrap();
+            value
+        };
+
+        assert_eq!(run(0), "{ }");
+        assert_eq!(run(1), "{ }");
+        fs::write(&module, "{ a = 1; }").unwrap();
+        assert_eq!(run(0), "{ a = 1; }");
+    }
+}
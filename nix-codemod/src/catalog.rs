@@ -0,0 +1,246 @@
+
+use std::fs;
+use std::fmt;
+use std::error::Error;
+
+use serde::Deserialize;
+
+use crate::walkers::{quote_string, CfgValue};
+use crate::nixpkgs::systemd_version;
+
+static BUILTIN_CATALOG: &str = include_str!("../catalog.json");
+
+/// A value a hardening option can be set to
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(untagged)]
+pub enum OptionValue {
+    Bool(bool),
+    Str(String),
+    List(Vec<String>),
+}
+
+impl fmt::Display for OptionValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            OptionValue::Bool(b) => write!(f, "{}", b),
+            OptionValue::Str(s) => write!(f, "{}", quote_string(s)),
+            OptionValue::List(elems) => {
+                write!(f, "[ ")?;
+                for elem in elems {
+                    write!(f, "{} ", quote_string(elem))?;
+                }
+                write!(f, "]")
+            },
+        }
+    }
+}
+
+impl OptionValue {
+    /// Whether `value` is known to be this value
+    pub fn matches(&self, value: &CfgValue) -> bool {
+        match (self, value) {
+            (_, CfgValue::Prioritized { inner, .. }) => self.matches(inner),
+            (OptionValue::Bool(a), CfgValue::Bool(b)) => a == b,
+            (OptionValue::Str(a), CfgValue::Str(b)) => a == b,
+            (OptionValue::List(a), CfgValue::List(b)) =>
+                a.len() == b.len() && a.iter().zip(b.iter()).all(|(a, b)| matches!(b, CfgValue::Str(b) if a == b)),
+            _ => false,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ValueType {
+    Bool,
+    /// One of a few strings, sometimes along with booleans
+    /// (`ProtectSystem = true` is the same as `"yes"`)
+    Enum,
+    /// A list of strings, like system calls or address families
+    List,
+}
+
+impl fmt::Display for ValueType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ValueType::Bool => write!(f, "bool"),
+            ValueType::Enum => write!(f, "enum"),
+            ValueType::List => write!(f, "list"),
+        }
+    }
+}
+
+impl ValueType {
+    fn admits(&self, value: &OptionValue) -> bool {
+        matches!((self, value),
+            (ValueType::Bool, OptionValue::Bool(_))
+            | (ValueType::Enum, OptionValue::Bool(_) | OptionValue::Str(_))
+            | (ValueType::List, OptionValue::List(_)))
+    }
+}
+
+/// A sandboxing setting of `serviceConfig`, along with the values
+/// worth trying for it
+#[derive(Clone, Debug, Deserialize)]
+pub struct HardeningOption {
+    pub name: String,
+    #[serde(rename = "type")]
+    pub value_type: ValueType,
+    /// From the least to the most restrictive; the first one is what
+    /// the default passthru sets.
+    pub values: Vec<OptionValue>,
+    #[serde(default)]
+    pub description: String,
+    #[serde(default)]
+    pub min_systemd_version: Option<u32>,
+    /// What systemd does when the option isn't set, if not the weakest value
+    #[serde(default)]
+    unset: Option<OptionValue>,
+    /// The value we'd like the service to run with, if not the most restrictive one
+    #[serde(default)]
+    secure: Option<OptionValue>,
+}
+
+impl HardeningOption {
+    pub fn weakest(&self) -> &OptionValue {
+        &self.values[0]
+    }
+
+    pub fn unset(&self) -> &OptionValue {
+        self.unset.as_ref().unwrap_or_else(|| self.weakest())
+    }
+
+    pub fn secure(&self) -> &OptionValue {
+        self.secure.as_ref().unwrap_or_else(|| &self.values[self.values.len() - 1])
+    }
+
+    /// Whether hooking this option is worth it, given its current value.
+    /// A hook keeps the service as it was only if the option was at its
+    /// weakest value, so we don't hook anything else.
+    pub fn is_blank(&self, value: Option<&CfgValue>) -> bool {
+        let current_is_weakest = match value {
+            Some(value) => self.weakest().matches(value),
+            None => self.unset() == self.weakest(),
+        };
+        current_is_weakest && self.weakest() != self.secure()
+    }
+
+    pub fn is_supported_by(&self, systemd_version: u32) -> bool {
+        self.min_systemd_version.is_none_or(|v| v <= systemd_version)
+    }
+
+    /// Whether the option can be set to `value`
+    pub fn admits(&self, value: &CfgValue) -> bool {
+        self.values.iter().any(|v| v.matches(value))
+    }
+
+    fn check(&self) -> Result<(), Box<dyn Error>> {
+        if self.values.is_empty() {
+            Err(format!("option {} has no values", self.name))?
+        }
+        for value in self.values.iter().chain(self.unset.iter()) {
+            if !self.value_type.admits(value) {
+                Err(format!("option {}: {} is not a {} value", self.name, value, self.value_type))?
+            }
+        }
+        if !self.values.contains(self.secure()) {
+            Err(format!("option {}: the secure value {} isn't one of its values", self.name, self.secure()))?
+        }
+        Ok(())
+    }
+}
+
+#[derive(Deserialize)]
+struct Catalog {
+    options: Vec<HardeningOption>,
+}
+
+fn parse_catalog(content: &str, toml: bool) -> Result<Vec<HardeningOption>, Box<dyn Error>> {
+    let catalog: Catalog = if toml {
+        toml::from_str(content)?
+    } else {
+        serde_json::from_str(content)?
+    };
+    for opt in catalog.options.iter() {
+        opt.check()?;
+    }
+    Ok(catalog.options)
+}
+
+pub fn builtin_catalog() -> Vec<HardeningOption> {
+    parse_catalog(BUILTIN_CATALOG, false).expect("the built-in catalog is well-formed")
+}
+
+/// Reads the catalog at `path` (TOML if it ends with `.toml`, JSON otherwise),
+/// or the built-in one
+pub fn load_catalog(path: Option<&str>) -> Result<Vec<HardeningOption>, Box<dyn Error>> {
+    match path {
+        Some(path) => {
+            let content = fs::read_to_string(path)?;
+            parse_catalog(&content, path.ends_with(".toml"))
+                .map_err(|e| format!("{}: {}", path, e).into())
+        },
+        None => Ok(builtin_catalog()),
+    }
+}
+
+/// Like `load_catalog`, without the options the systemd of `nixpkgs` doesn't have
+pub fn load_target_catalog(path: Option<&str>, nixpkgs: Option<&str>) -> Result<Vec<HardeningOption>, Box<dyn Error>> {
+    let mut catalog = load_catalog(path)?;
+    if let Some(nixpkgs) = nixpkgs {
+        let version = systemd_version(nixpkgs)?;
+        catalog.retain(|opt| opt.is_supported_by(version));
+    }
+    Ok(catalog)
+}
+
+pub fn find_option<'a>(catalog: &'a [HardeningOption], name: &str) -> Option<&'a HardeningOption> {
+    catalog.iter().find(|opt| opt.name == name)
+}
+
+#[cfg(test)]
+mod catalog_tests {
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+
+    #[test]
+    fn test_builtin_catalog() {
+        let catalog = builtin_catalog();
+        let delegate = find_option(&catalog, "Delegate").unwrap();
+        assert_eq!(delegate.secure(), &OptionValue::Bool(false));
+        assert_eq!(delegate.unset(), &OptionValue::Bool(false));
+        assert!(!delegate.is_blank(None));
+        assert!(find_option(&catalog, "PrivateTmp").unwrap().is_blank(None));
+        assert!(!find_option(&catalog, "ProtectProc").unwrap().is_supported_by(246));
+    }
+
+    #[test]
+    fn test_toml_catalog() {
+        let catalog = parse_catalog(r#"
+            [[options]]
+            name = "ProtectSystem"
+            type = "enum"
+            values = [false, true, "full", "strict"]
+            description = "Make the file system read-only"
+            min_systemd_version = 214
+
+            [[options]]
+            name = "SystemCallFilter"
+            type = "list"
+            values = [[], ["@system-service"]]
+        "#, true).unwrap();
+
+        assert_eq!(catalog.len(), 2);
+        assert_eq!(catalog[0].secure(), &OptionValue::Str("strict".to_string()));
+        assert_eq!(catalog[0].min_systemd_version, Some(214));
+        assert_eq!(catalog[1].weakest(), &OptionValue::List(vec!()));
+    }
+
+    #[test]
+    fn test_ill_typed_catalog() {
+        assert!(parse_catalog(r#"
+            { "options": [ { "name": "PrivateTmp", "type": "bool", "values": [false, "yes"] } ] }
+        "#, false).is_err());
+    }
+}
@@ -0,0 +1,235 @@
+
+use std::fs;
+use std::error::Error;
+use std::path::Path;
+use std::collections::BTreeMap;
+
+use serde::{Serialize, Deserialize};
+
+#[derive(Deserialize)]
+struct Target {
+    module: String,
+    service: String,
+    blank: Vec<String>,
+}
+
+#[derive(Deserialize)]
+struct Failure {
+    module: String,
+    reason: String,
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+struct Service {
+    service: String,
+    module: String,
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+struct Moved {
+    service: String,
+    old_module: String,
+    new_module: String,
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+struct Configured {
+    service: String,
+    module: String,
+    /// Options that were blank, and that upstream sets now
+    options: Vec<String>,
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+struct Irreducible {
+    module: String,
+    reason: String,
+}
+
+#[derive(Debug, Default, PartialEq, Serialize)]
+struct DiscoveryDiff {
+    new_services: Vec<Service>,
+    removed_services: Vec<Service>,
+    moved_services: Vec<Moved>,
+    configured_options: Vec<Configured>,
+    newly_irreducible: Vec<Irreducible>,
+}
+
+/// Module paths are absolute, so the same module has a different path in
+/// two checkouts
+fn relative_module(module: &str) -> String {
+    match module.rfind("nixos/modules/") {
+        Some(i) => module[i + "nixos/modules/".len()..].to_string(),
+        None => module.to_string(),
+    }
+}
+
+fn by_service(targets: &[Target]) -> BTreeMap<&str, &Target> {
+    targets.iter().map(|t| (t.service.as_str(), t)).collect()
+}
+
+fn diff(old: &[Target], new: &[Target], old_failures: &[Failure], new_failures: &[Failure]) -> DiscoveryDiff {
+    let relative = |targets: &[Target]| -> Vec<Target> {
+        targets.iter()
+            .map(|t| Target { module: relative_module(&t.module), service: t.service.clone(), blank: t.blank.clone() })
+            .collect()
+    };
+    let (old, new) = (&relative(old), &relative(new));
+    let old_failures: Vec<String> = old_failures.iter().map(|f| relative_module(&f.module)).collect();
+
+    let old_services = by_service(old);
+    let new_services = by_service(new);
+    let mut diff = DiscoveryDiff::default();
+
+    for (name, t) in new_services.iter() {
+        if !old_services.contains_key(name) {
+            diff.new_services.push(Service { service: name.to_string(), module: t.module.clone() });
+        }
+    }
+
+    for (name, old) in old_services.iter() {
+        let new = match new_services.get(name) {
+            Some(new) => new,
+            None => {
+                diff.removed_services.push(Service { service: name.to_string(), module: old.module.clone() });
+                continue
+            },
+        };
+
+        if old.module != new.module {
+            diff.moved_services.push(Moved {
+                service: name.to_string(),
+                old_module: old.module.clone(),
+                new_module: new.module.clone(),
+            });
+        }
+
+        let options: Vec<String> = old.blank.iter()
+            .filter(|opt| !new.blank.contains(opt))
+            .cloned()
+            .collect();
+        if !options.is_empty() {
+            diff.configured_options.push(Configured { service: name.to_string(), module: new.module.clone(), options });
+        }
+    }
+
+    for failure in new_failures.iter() {
+        let module = relative_module(&failure.module);
+        if !old_failures.contains(&module) {
+            diff.newly_irreducible.push(Irreducible { module, reason: failure.reason.clone() });
+        }
+    }
+
+    diff
+}
+
+fn read_json<T: for<'a> Deserialize<'a>>(path: &str) -> Result<T, Box<dyn Error>> {
+    let content = fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+    Ok(serde_json::from_str(&content).map_err(|e| format!("{}: {}", path, e))?)
+}
+
+/// The failures written along with `discovery`, if any
+fn read_failures(discovery: &str, failures: Option<&str>) -> Result<Vec<Failure>, Box<dyn Error>> {
+    match failures {
+        Some(path) => read_json(path),
+        None => {
+            let path = format!("{}.failures", discovery);
+            if Path::new(&path).exists() { read_json(&path) } else { Ok(vec!()) }
+        },
+    }
+}
+
+fn print_summary(diff: &DiscoveryDiff) {
+    eprintln!("{} new services", diff.new_services.len());
+    for Service { service, module } in diff.new_services.iter() {
+        eprintln!(" + {} ({})", service, module);
+    }
+    eprintln!("{} removed services", diff.removed_services.len());
+    for Service { service, module } in diff.removed_services.iter() {
+        eprintln!(" - {} ({})", service, module);
+    }
+    eprintln!("{} moved services", diff.moved_services.len());
+    for Moved { service, old_module, new_module } in diff.moved_services.iter() {
+        eprintln!(" > {}: {} -> {}", service, old_module, new_module);
+    }
+    eprintln!("{} services with newly configured options", diff.configured_options.len());
+    for Configured { service, options, .. } in diff.configured_options.iter() {
+        eprintln!(" * {}: {}", service, options.join(", "));
+    }
+    eprintln!("{} newly irreducible modules", diff.newly_irreducible.len());
+    for Irreducible { module, reason } in diff.newly_irreducible.iter() {
+        eprintln!(" ! {}: {}", module, reason);
+    }
+}
+
+pub fn diff_discovery(
+    old: &str,
+    new: &str,
+    old_failures: Option<&str>,
+    new_failures: Option<&str>
+) -> Result<(), Box<dyn Error>> {
+    let diff = diff(
+        &read_json::<Vec<Target>>(old)?,
+        &read_json::<Vec<Target>>(new)?,
+        &read_failures(old, old_failures)?,
+        &read_failures(new, new_failures)?);
+
+    print_summary(&diff);
+    println!("{}", serde_json::to_string(&diff)?);
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod diff_tests {
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+
+    fn target(module: &str, service: &str, blank: &[&str]) -> Target {
+        Target {
+            module: module.to_string(),
+            service: service.to_string(),
+            blank: blank.iter().map(|s| s.to_string()).collect(),
+        }
+    }
+
+    fn failure(module: &str) -> Failure {
+        Failure { module: module.to_string(), reason: "couldn't reduce".to_string() }
+    }
+
+    #[test]
+    fn test_diff() {
+        let old = vec![
+            target("/old/nixos/modules/services/a.nix", "a", &["PrivateTmp", "ProtectHome"]),
+            target("services/b.nix", "b", &[]),
+            target("services/c.nix", "c", &["PrivateTmp"]),
+        ];
+        let new = vec![
+            target("services/a.nix", "a", &["ProtectHome"]),
+            target("services/misc/b.nix", "b", &[]),
+            target("services/d.nix", "d", &["PrivateTmp"]),
+        ];
+        let old_failures = vec![failure("services/e.nix")];
+        let new_failures = vec![failure("/new/nixos/modules/services/e.nix"), failure("services/c.nix")];
+
+        assert_eq!(diff(&old, &new, &old_failures, &new_failures), DiscoveryDiff {
+            new_services: vec![Service { service: "d".to_string(), module: "services/d.nix".to_string() }],
+            removed_services: vec![Service { service: "c".to_string(), module: "services/c.nix".to_string() }],
+            moved_services: vec![Moved {
+                service: "b".to_string(),
+                old_module: "services/b.nix".to_string(),
+                new_module: "services/misc/b.nix".to_string(),
+            }],
+            configured_options: vec![Configured {
+                service: "a".to_string(),
+                module: "services/a.nix".to_string(),
+                options: vec!["PrivateTmp".to_string()],
+            }],
+            newly_irreducible: vec![Irreducible {
+                module: "services/c.nix".to_string(),
+                reason: "couldn't reduce".to_string(),
+            }],
+        });
+    }
+}
@@ -0,0 +1,254 @@
+
+use std::fs;
+use std::error::Error;
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+use std::collections::{BTreeMap, BTreeSet};
+
+use serde::{Serialize, Deserialize};
+use rayon::prelude::*;
+
+use crate::catalog::*;
+use crate::cache::*;
+use crate::error::{error_category, catch_panic};
+use crate::location::{Location, FileError};
+use crate::imports::module_files;
+use crate::module_list::module_list;
+use super::{ServiceDecl, module_services, service_config};
+
+#[derive(Clone, Serialize, Deserialize)]
+struct Target {
+    module: String,
+    /// The file that declares the service, `module` or one of its imports
+    file: String,
+    service: String,
+    /// Where the service is declared
+    location: Location,
+    blank: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct Failure {
+    module: String,
+    /// The service that couldn't be reduced, if not the whole module
+    #[serde(skip_serializing_if = "Option::is_none")]
+    service: Option<String>,
+    reason: String,
+    category: String,
+    location: Option<Location>,
+}
+
+/// Why a module, or one of its services, couldn't be reduced
+#[derive(Clone, Serialize, Deserialize)]
+struct ModuleError {
+    reason: String,
+    category: String,
+    location: Option<Location>,
+}
+
+impl ModuleError {
+    fn new(e: Box<dyn Error>) -> ModuleError {
+        let category = error_category(e.as_ref()).to_string();
+        match e.downcast::<FileError>() {
+            Ok(e) => ModuleError { reason: e.message(), category, location: e.location.clone() },
+            Err(e) => ModuleError { reason: e.to_string(), category, location: None },
+        }
+    }
+}
+
+/// A service of a module that couldn't be reduced
+#[derive(Clone, Serialize, Deserialize)]
+struct ServiceError {
+    service: String,
+    error: ModuleError,
+}
+
+/// The services of a module and their blank options, and the services that
+/// couldn't be reduced
+fn discover_module(module: &Path, catalog: &[HardeningOption]) -> Result<(Vec<Target>, Vec<ServiceError>), Box<dyn Error>> {
+    let (services, _, errors) = module_services(module)?;
+    let mut errors: Vec<ServiceError> = errors.into_iter()
+        .map(|(service, e)| ServiceError { service, error: ModuleError::new(e) })
+        .collect();
+
+    let mut targets = vec!();
+    for ServiceDecl { service, location } in services {
+        match catch_panic(|| service_config(module, &service, catalog)) {
+            Ok(config) => {
+                let module = module.display().to_string();
+                targets.push(Target { module, file: location.file.clone(), service, location, blank: config.blank });
+            },
+            Err(e) => errors.push(ServiceError { service, error: ModuleError::new(e) }),
+        }
+    }
+
+    Ok((targets, errors))
+}
+
+type Outcome = Result<(Vec<Target>, Vec<ServiceError>), ModuleError>;
+
+pub fn discover_systemd_services(
+    nixpkgs: &str,
+    output: &str,
+    failures_output: Option<&str>,
+    prefixes: &[String],
+    catalog: Option<&str>,
+    cache_dir: Option<&str>,
+    no_cache: bool
+) -> Result<(), Box<dyn Error>> {
+    let start = Instant::now();
+    let catalog = load_target_catalog(catalog, Some(nixpkgs))?;
+    let modules = module_list(nixpkgs, prefixes)?;
+
+    let cache_path = if no_cache {
+        None
+    } else {
+        cache_dir.map(PathBuf::from).or_else(default_cache_dir).map(|dir| dir.join("discovery.json"))
+    };
+    let cache: Cache<Outcome> = Cache::load(cache_path);
+    // the blank options depend on the catalog too
+    let fingerprint = format!("{:?}", catalog);
+
+    let outcomes: Vec<Outcome> = modules.par_iter()
+        .map(|module| {
+            let content = fs::read(module).unwrap_or_default();
+            let key = hash(&[fingerprint.as_bytes(), module.to_string_lossy().as_bytes(), &content]);
+            cache.get_or_compute(key, || {
+                let outcome = catch_panic(|| discover_module(module, &catalog))
+                    .map_err(ModuleError::new);
+                let files = module_files(module).unwrap_or_else(|_| vec![module.clone()]);
+                (outcome, files)
+            })
+        })
+        .collect();
+
+    let stats = cache.stats();
+    cache.save()?;
+
+    let mut targets: Vec<Target> = vec!();
+    let mut failures: Vec<Failure> = vec!();
+
+    for (module, outcome) in modules.iter().zip(outcomes) {
+        let module = module.display().to_string();
+        match outcome {
+            Ok((found, errors)) => {
+                targets.extend(found);
+                for ServiceError { service, error: ModuleError { reason, category, location } } in errors {
+                    failures.push(Failure { module: module.clone(), service: Some(service), reason, category, location });
+                }
+            },
+            Err(ModuleError { reason, category, location }) =>
+                failures.push(Failure { module, service: None, reason, category, location }),
+        }
+    }
+
+    fs::write(output, serde_json::to_string(&targets)?)?;
+
+    if let Some(failures_output) = failures_output {
+        fs::write(failures_output, serde_json::to_string(&failures)?)?;
+    } else {
+        for Failure { module, service, reason, location, .. } in failures.iter() {
+            let reason = match service {
+                Some(service) => format!("{}: {}", service, reason),
+                None => reason.clone(),
+            };
+            match location {
+                Some(location) => eprintln!("{}: {}", location, reason),
+                None => eprintln!("{}: {}", module, reason),
+            }
+        }
+    }
+
+    // the constructs that would unlock the most modules first
+    let mut categories: BTreeMap<&str, BTreeSet<&str>> = BTreeMap::new();
+    for failure in failures.iter() {
+        categories.entry(&failure.category).or_default().insert(&failure.module);
+    }
+    let mut categories: Vec<(&str, usize)> = categories.into_iter()
+        .map(|(category, modules)| (category, modules.len()))
+        .collect();
+    categories.sort_by(|(_, a), (_, b)| b.cmp(a));
+
+    let n = modules.len();
+    let failed: BTreeSet<&str> = failures.iter().map(|f| f.module.as_str()).collect();
+    let success = n - failed.len();
+    println!("{} ({}%) succeeded", success, (success * 100).checked_div(n).unwrap_or(100));
+    println!("{} services discovered", targets.len());
+    for (category, count) in categories {
+        println!(" * {}: {} modules", category, count);
+    }
+    println!("cache: {} hits, {} misses, {}ms parsing, {}ms in total",
+        stats.hits, stats.misses, stats.compute_time.as_millis(), start.elapsed().as_millis());
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod discover_tests {
+    use pretty_assertions::assert_eq;
+    use serde_json::{Value, json};
+
+    use crate::test_dir::TestDir;
+    use super::*;
+
+    #[test]
+    fn test_discover() {
+        let dir = TestDir::new("discover");
+        dir.write("pkgs/os-specific/linux/systemd/default.nix", "{ }: { version = \"254.6\"; }");
+        dir.write("nixos/modules/module-list.nix", "\
+[
+  ./services/foo
+  ./services/broken.nix
+]
+");
+        let foo = dir.write("nixos/modules/services/foo/default.nix", "\
+{ config, lib, ... }: {
+  imports = [ ./service.nix ];
+}
+");
+        let service = dir.write("nixos/modules/services/foo/service.nix", "\
+{ config, lib, ... }: {
+  config.systemd.services.foo.serviceConfig = {
+    PrivateTmp = true;
+  };
+  config.systemd.services.bar.serviceConfig = lib.mapAttrs f cfg.settings;
+}
+");
+        let broken = dir.write("nixos/modules/services/broken.nix", "\
+{ config, lib, ... }: {
+  config = lib.mapAttrs f cfg.instances;
+}
+");
+        let path = |name: &str| dir.path().join(name).display().to_string();
+        let (output, failures) = (path("discovery.json"), path("failures.json"));
+        discover_systemd_services(&path(""), &output, Some(&failures), &[], None, None, true).unwrap();
+        let read = |file: &str| serde_json::from_str::<Value>(&fs::read_to_string(file).unwrap()).unwrap();
+
+        let mut targets = read(&output);
+        let blank = targets[0]["blank"].take();
+        assert_eq!(targets, json!([{
+            "module": foo.display().to_string(),
+            "file": service.display().to_string(),
+            "service": "foo",
+            "location": { "file": service.display().to_string(), "line": 2, "column": 3 },
+            "blank": null,
+        }]));
+        let blank: Vec<&str> = blank.as_array().unwrap().iter().map(|name| name.as_str().unwrap()).collect();
+        assert!(blank.contains(&"ProtectHome"));
+        assert!(!blank.contains(&"PrivateTmp"));
+
+        let broken = broken.display().to_string();
+        assert_eq!(read(&failures), json!([{
+            "module": foo.display().to_string(),
+            "service": "bar",
+            "reason": "couldn't reduce function application",
+            "category": "function application",
+            "location": { "file": service.display().to_string(), "line": 5, "column": 47 },
+        }, {
+            "module": broken,
+            "reason": "couldn't reduce function application",
+            "category": "function application",
+            "location": { "file": broken, "line": 2, "column": 12 },
+        }]));
+    }
+}
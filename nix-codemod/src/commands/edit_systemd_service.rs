@@ -1,167 +1,537 @@
 
 use std::fs;
 use std::error::Error;
+use std::path::Path;
 use std::iter;
 
-use serde_json;
-
 use rnix::types::*;
-use rnix::SyntaxNode;
+use rnix::{SyntaxKind, SyntaxNode};
 
 use crate::walkers::*;
 use crate::edit::*;
-
-fn find_service_decl(root: Root, service: &str) -> Result<DeclValue, Box<dyn Error>> {
-    let x = root.inner().and_then(Lambda::cast).ok_or("root isn't a function")?;
-    let x = go_right_value(x.body().ok_or("parse error")?)?;
-    decl_value(
-        &[  "config".to_string(),
-            "systemd".to_string(),
-            "services".to_string(),
-            service.to_string()],
-        x)?.ok_or(format!("config.systemd.services.{} is not declared", service).into())
-}
+use crate::catalog::*;
+use crate::imports::find_declaring_file;
+use crate::nixpkgs::systemd_version;
+use crate::error::{CodemodError, parse_error, unsupported};
+use crate::location::in_file;
+use crate::source::parse_file;
+use crate::diff::{relative_path, unified_diff};
 
 fn modify_attribute_set(n: SyntaxNode, replacements: &[(String, String)]) -> Result<Vec<Edit>, Box<dyn Error>> {
-    let n = match ParsedType::try_from(n)? {
-        ParsedType::AttrSet(o) => o,
-        _ => panic!("expected an attribute set")
+    let n = go_right_value(n)?;
+    let names: Vec<String> = replacements.iter().map(|(k, _)| k.clone()).collect();
+    let n = match update_target(&n, &names) {
+        Some(target) => target,
+        // an operand may override the entries of the chain we write to:
+        // the replacements go to a set of their own, at its end
+        None if update_operands(&n).is_some() => {
+            let lines = replacements.iter()
+                .map(|(k, v)| format!("{} = {};", k, v))
+                .collect::<Vec<String>>();
+            return Ok(vec![append_update(&n, &lines)])
+        },
+        None => n,
     };
+    let n = AttrSet::cast(n.clone()).ok_or_else(|| unsupported(&n))?;
 
-    let to_remove = n.entries().map(|e| {
-        let keys = e.key().ok_or("parse error")?.path()
-            .map(parse_ident)
+    let mut edits = Vec::new();
+    let mut declared = Vec::new();
+
+    // the keys already there keep their place, and the comments around
+    // them: only their value changes
+    for e in n.entries() {
+        let keys = e.key().ok_or_else(|| parse_error(e.node()))?.path()
+            .map(parse_key_part)
             .collect::<Result<Vec<_>, Box<dyn Error>>>()?;
 
-        for (k, _) in replacements.iter() {
-            if keys == [Some(k.to_string())] {
-                return Ok(Some(e))
+        for (k, v) in replacements.iter() {
+            if matches!(&keys[..], [key] if key.is(k)) {
+                let value = e.value().ok_or_else(|| parse_error(e.node()))?;
+                edits.push(Edit {
+                    start: value.text_range().start().into(),
+                    end: value.text_range().end().into(),
+                    replace: v.clone(),
+                });
+                declared.push(k.clone());
             }
         }
+    }
 
-        Ok(None)
-    }).collect::<Result<Vec<_>, Box<dyn Error>>>()?.into_iter().filter_map(|o| o);
-
-
-    let mut edits = Vec::new();
-
-    for n in to_remove {
-        edits.push(remove_node(&n.node()));
+    let names: Vec<String> = replacements.iter()
+        .filter(|(k, _)| !declared.contains(k))
+        .map(|(k, _)| k.clone())
+        .collect();
+    for inherit in n.inherits() {
+        edits.append(&mut remove_inherited(inherit.node(), &names));
     }
 
-    let indent = guess_indent(n.node())?.unwrap_or(0);
     let lines = replacements.iter()
+        .filter(|(k, _)| !declared.contains(k))
         .map(|(k, v)| format!("{} = {};", k, v))
         .collect::<Vec<String>>();
-    edits.push(insert_at_set_end(n.node(), &lines, indent)?);
+    if !lines.is_empty() {
+        let indent = guess_indent(n.node())?;
+        let mut inserted = insert_at_set_end(n.node(), &lines, &indent, &edits)?;
+        edits.append(&mut inserted);
+    }
 
     Ok(edits)
 }
 
+/// The lines of the entry `kv`, declared as `<prefix>.<key> = ...;`, once
+/// moved into a `<prefix> = { ... };` block: its original text from `key` on,
+/// with the comments around it, and its value replaced if `replacements` has
+/// `key`
+fn merged_entry_lines(key: &[KeyPart], kv: &DeclKV, replacements: &[(String, String)]) -> Result<Vec<String>, Box<dyn Error>> {
+    let n = KeyValue::cast(kv.node.clone()).ok_or_else(|| unsupported(&kv.node))?;
+    let path: Vec<SyntaxNode> = n.key().ok_or_else(|| parse_error(&kv.node))?.path().collect();
+    if key.is_empty() || path.len() < key.len() {
+        Err(unsupported(&kv.node))?
+    }
+
+    let offset = |n: &SyntaxNode| usize::from(n.text_range().start()) - usize::from(kv.node.text_range().start());
+    let mut text = kv.node.text().to_string();
+    if let Some((_, v)) = replacements.iter().find(|(k, _)| key == [KeyPart::Static(k.to_string())]) {
+        let value = n.value().ok_or_else(|| parse_error(&kv.node))?;
+        let start = offset(&value);
+        text.replace_range(start..start + usize::from(value.text_range().len()), v);
+    }
+    let text = &text[offset(&path[path.len() - key.len()])..];
+
+    let indent = line_indent(&kv.node).unwrap_or_default();
+    let mut lines: Vec<String> = leading_comments(&kv.node).iter()
+        .flat_map(|comment| unindent(comment.text(), &indent))
+        .chain(unindent(text, &indent))
+        .collect();
+    if let (Some(comment), Some(last)) = (trailing_comment(&kv.node), lines.last_mut()) {
+        last.push(' ');
+        last.push_str(comment.text());
+    }
+    Ok(lines)
+}
+
 fn merge_decls(
     n: &SyntaxNode,
     prefix: &[String],
-    entries: &[(Vec<String>, DeclKV)],
+    entries: &[(Vec<KeyPart>, DeclKV)],
     replacements: &[(String, String)]
 ) -> Result<Vec<Edit>, Box<dyn Error>> {
-    let indent = guess_indent(n)?.unwrap_or(0);
-    let lines = iter::once(format!("{} = {{", prefix.join(".")))
-        .chain(entries.iter().filter_map(|(key, DeclKV { value, .. })| {
-            for (k, _) in replacements {
-                if key == &[k.to_string()] {
-                    return None
-                }
-            }
-            Some(format!("  {} = {};", key.join("."), value))
-        }))
+    // a chain of `//` without a set we can write to
+    AttrSet::cast(n.clone()).ok_or_else(|| unsupported(n))?;
+    let indent = guess_indent(n)?;
+    let mut entry_lines = vec!();
+    for (key, kv) in entries {
+        entry_lines.append(&mut merged_entry_lines(key, kv, replacements)?);
+    }
+    let lines = iter::once(format!("{} = {{", prefix.iter().map(|k| quote_attr(k)).collect::<Vec<_>>().join(".")))
+        .chain(entry_lines.into_iter()
+            .map(|line| if line.is_empty() { line } else { format!("{}{}", indent.unit, line) }))
         .chain(replacements.iter()
-            .map(|(k, v)| format!("  {} = {};", k, v)))
+            .filter(|(k, _)| !entries.iter().any(|(key, _)| key == &[KeyPart::Static(k.to_string())]))
+            .map(|(k, v)| format!("{}{} = {};", indent.unit, k, v)))
         .chain(iter::once("};".to_string()))
         .collect::<Vec<String>>();
-    let edits = entries.iter()
-        .map(|(_, DeclKV { node, .. })| remove_node(&node))
-        .chain(iter::once(insert_at_set_end(n, &lines, indent)?))
+    let mut edits = entries.iter()
+        .map(|(_, DeclKV { node, .. })| remove_entry(node))
         .collect::<Vec<Edit>>();
+    let mut inserted = insert_at_set_end(n, &lines, &indent, &edits)?;
+    edits.append(&mut inserted);
 
     Ok(edits)
 }
 
 fn add_attribute_decl(n: &SyntaxNode, prefix: &[String], replacements: &[(String, String)]) -> Result<Vec<Edit>, Box<dyn Error>> {
-    let inherited: Vec<String> = AttrSet::cast(n.clone()).ok_or("parse error")?.inherits()
-        .map(|inherit| inherit.idents().map(|ident| ident.as_str().to_string()))
-        .flatten()
-        .collect();
-
-    if inherited.contains(&"serviceConfig".to_string()) {
-        Err("can't replace an attribute that is inherited")?
-    }
-
-    let indent = guess_indent(n)?.unwrap_or(0);
+    let n = &resolve_value(n.clone())?;
+    let name = prefix.first().cloned().unwrap_or_else(|| "serviceConfig".to_string());
+    let n = &update_target(n, &[name]).ok_or("couldn't find where to declare serviceConfig")?;
+    let indent = guess_indent(n)?;
     let lines = iter::once(format!("{}serviceConfig = {{",
-            prefix.iter().map(|k| format!("{}.", k)).collect::<String>()))
+            prefix.iter().map(|k| format!("{}.", quote_attr(k))).collect::<String>()))
         .chain(replacements.iter()
-            .map(|(k, v)| format!("  {} = {};", k, v))
+            .map(|(k, v)| format!("{}{} = {};", indent.unit, k, v))
             .collect::<Vec<String>>())
         .chain(iter::once("};".to_string()))
         .collect::<Vec<String>>();
-    let edit = insert_at_set_end(n, &lines, indent)?;
 
-    Ok(vec![edit])
+    insert_at_set_end(n, &lines, &indent, &[])
 }
 
-pub fn edit_systemd_service(module: &str, service: &str, options: &str, verbose: bool) -> Result<(), Box<dyn Error>> {
-    let content = fs::read_to_string(&module)?;
-    let ast = rnix::parse(&content).as_result()?;
+/// Declares `replacements` in a `serviceConfig` reached through a
+/// binding (`inherit serviceConfig;`, `serviceConfig = common;`, ...)
+/// that other declarations also use, without touching their value:
+/// the reference becomes `common // { ... }`.
+fn split_decl(kv: &DeclKV, replacements: &[(String, String)]) -> Result<Vec<Edit>, Box<dyn Error>> {
+    let set = kv.node.parent().ok_or_else(|| parse_error(&kv.node))?;
+    let indent = guess_indent(&set)?;
+    let entries = replacements.iter()
+        .map(|(k, v)| format!("{}{} = {};", indent.unit, k, v))
+        .collect::<Vec<String>>();
 
-    let decl = find_service_decl(ast.root(), &service)?;
-    let cfg = decl.clone().project("serviceConfig")?;
+    if kv.is_inherited() {
+        if AttrSet::cast(set.clone()).ok_or_else(|| unsupported(&set))?.recursive() {
+            Err(CodemodError::Inherit(kv.node.text_range()))?
+        }
 
-    let options: Vec<(String, String)> = {
-        let content = fs::read_to_string(&options)?;
-        serde_json::from_str(&content)?
-    };
-    
-    let edits = match cfg {
+        let name = kv.value.text().to_string();
+        let base = match Inherit::cast(kv.node.clone()).and_then(|i| i.from()) {
+            Some(from) => match from.inner() {
+                Some(inner) if matches!(inner.kind(), SyntaxKind::NODE_IDENT | SyntaxKind::NODE_SELECT) =>
+                    format!("{}.{}", inner, name),
+                _ => format!("{}.{}", from.node(), name),
+            },
+            None => name.clone(),
+        };
+
+        let lines = iter::once(format!("{} = {} // {{", name, base))
+            .chain(entries)
+            .chain(iter::once("};".to_string()))
+            .collect::<Vec<String>>();
+
+        let mut edits = remove_inherited(&kv.node, &[name]);
+        let mut inserted = insert_at_set_end(&set, &lines, &indent, &edits)?;
+        edits.append(&mut inserted);
+        Ok(edits)
+    } else {
+        match ParsedType::try_from(kv.value.clone())? {
+            ParsedType::Ident(_) | ParsedType::Select(_) => (),
+            _ => Err(unsupported(&kv.value))?,
+        }
+
+        let base = line_indent_of(&kv.value);
+        let replace = format!("{} // {{{}\n{}}}",
+            kv.value,
+            entries.iter().map(|l| format!("\n{}{}", base, l)).collect::<String>(),
+            base);
+        // an element of a list is a single operand
+        let replace = if kv.value.parent().is_some_and(|p| p.kind() == SyntaxKind::NODE_LIST) {
+            format!("({})", replace)
+        } else {
+            replace
+        };
+        Ok(vec![replace_node(&kv.value, replace)])
+    }
+}
+
+/// Declares `replacements` in the value of `kv`: in place if it's written
+/// there or if nothing else uses it, otherwise by splitting the binding.
+fn modify_decl(kv: &DeclKV, replacements: &[(String, String)]) -> Result<Vec<Edit>, Box<dyn Error>> {
+    let target = resolve_value(kv.value.clone())?;
+
+    if AttrSet::cast(target.clone()).is_none() && update_operands(&target).is_none() {
+        if kv.is_inherited() {
+            Err(CodemodError::Inherit(kv.node.text_range()))?
+        } else {
+            Err(unsupported(&target))?
+        }
+    }
+
+    let in_place = kv.node.text_range().contains_range(target.text_range());
+    if in_place || count_references(&target) <= 1 {
+        modify_attribute_set(target, replacements)
+    } else {
+        split_decl(kv, replacements)
+    }
+}
+
+fn service_config_edits(
+    service: &str,
+    decl: &DeclValue,
+    cfg: Option<DeclValue>,
+    replacements: &[(String, String)],
+    verbose: bool
+) -> Result<Vec<Edit>, Box<dyn Error>> {
+    match cfg {
         Some(DeclValue::Node(n)) => {
+            // `serviceConfig = mkMerge [ ... ]` is declared by each element
+            if let Some(elems) = merge_elements(&resolve_value(n.value.clone())?)? {
+                let decls = elems.into_iter()
+                    .map(|value| DeclValue::Node(DeclKV { value, ..n.clone() }))
+                    .collect();
+                if let Some(cfg) = DeclValue::merged(decls) {
+                    return service_config_edits(service, decl, Some(cfg), replacements, verbose)
+                }
+            }
+
             if verbose {
-                println!("modify entries in already declared {}.serviceConfig", service);
+                eprintln!("modify entries in already declared {}.serviceConfig", service);
             }
 
-            modify_attribute_set(n.value, &options)?
+            modify_decl(&n, replacements)
         },
         Some(DeclValue::PartialAttr { node, prefix, entries }) => {
             if verbose {
-                println!("merge declarations in {} = {{ ... }}", prefix.join("."));
+                eprintln!("merge declarations in {} = {{ ... }}", prefix.join("."));
+            }
+
+            merge_decls(&node, &prefix, &entries, replacements)
+        },
+        Some(cfg @ DeclValue::Merged(_)) => {
+            // Only one element of the mkMerge gets the new entries, the
+            // other ones must not declare them anymore.
+            let primary = cfg.primary().clone();
+            let mut edits = vec!();
+
+            if let DeclValue::Merged(decls) = cfg {
+                if verbose {
+                    eprintln!("serviceConfig is declared in {} elements of a mkMerge", decls.len());
+                }
+
+                if primary.is_guarded() {
+                    return guarded_merge_edits(service, decl, &primary, decls, replacements, verbose)
+                }
+
+                for other in decls.iter().filter(|d| d.value() != primary.value()) {
+                    edits.append(&mut merged_element_edits(other, replacements)?);
+                }
             }
 
-            merge_decls(&node, &prefix, &entries, &options)?
+            edits.append(&mut service_config_edits(service, decl, Some(primary), replacements, verbose)?);
+            Ok(edits)
         },
         None => {
             if verbose {
-                println!("add {}.serviceConfig",
+                eprintln!("add {}.serviceConfig",
                     decl.prefix().iter().map(|k| format!("{}.", k)).collect::<String>());
             }
 
-            add_attribute_decl(&decl.value(), decl.prefix(), &options)?
+            add_attribute_decl(decl.value(), decl.prefix(), replacements)
         },
-    };
+    }
+}
 
-    let mut text = content.clone();
-    
-    if verbose {
-        println!("{}", text);
+/// The edits that keep `decl`, an element of a `mkMerge` that doesn't get
+/// the new entries, from declaring `replacements`: its entries are removed
+/// with their comments, unless they come from a binding other declarations
+/// also use, in which case `decl` sets them to the same values instead.
+fn merged_element_edits(decl: &DeclValue, replacements: &[(String, String)]) -> Result<Vec<Edit>, Box<dyn Error>> {
+    let mut edits = vec!();
+    let mut shared = vec!();
+
+    for (key, kv) in decl.clone().entries()?.unwrap_or_default() {
+        if let [KeyPart::Static(name)] = &key[..] {
+            if let Some(replacement) = replacements.iter().find(|(k, _)| k == name) {
+                let set = kv.node.parent().ok_or_else(|| parse_error(&kv.node))?;
+                if count_references(&set) > 1 {
+                    shared.push(replacement.clone());
+                } else if kv.is_inherited() {
+                    edits.append(&mut remove_inherited(&kv.node, std::slice::from_ref(name)));
+                } else {
+                    edits.push(remove_entry(&kv.node));
+                }
+            }
+        }
+    }
+
+    if !shared.is_empty() {
+        match decl {
+            DeclValue::Node(kv) => edits.append(&mut split_decl(kv, &shared)?),
+            decl => Err(unsupported(decl.value()))?,
+        }
+    }
+    Ok(edits)
+}
+
+/// The edits that set `replacements` in a `serviceConfig` declared by the
+/// elements `decls` of a `mkMerge`, which are all guarded: moving entries
+/// to `primary` would change when they apply, so they're replaced where
+/// they are, and only the new ones go to `primary`.
+fn guarded_merge_edits(
+    service: &str,
+    decl: &DeclValue,
+    primary: &DeclValue,
+    decls: Vec<DeclValue>,
+    replacements: &[(String, String)],
+    verbose: bool
+) -> Result<Vec<Edit>, Box<dyn Error>> {
+    let is_set = |entries: &DeclEntries, name: &str| entries.iter()
+        .any(|(key, _)| matches!(&key[..], [KeyPart::Static(k)] if k == name));
+    let decls = decls.into_iter()
+        .map(|d| Ok((d.clone().entries()?.unwrap_or_default(), d)))
+        .collect::<Result<Vec<_>, Box<dyn Error>>>()?;
+
+    let mut edits = vec!();
+    for (entries, d) in decls.iter() {
+        let is_primary = d.value() == primary.value();
+        let replaced: Vec<(String, String)> = replacements.iter()
+            .filter(|(k, _)| is_set(entries, k)
+                || is_primary && !decls.iter().any(|(entries, _)| is_set(entries, k)))
+            .cloned()
+            .collect();
+
+        match d {
+            _ if replaced.is_empty() => (),
+            DeclValue::PartialAttr { .. } if replaced.iter().all(|(k, _)| is_set(entries, k)) => {
+                for (key, kv) in entries.iter().filter(|(_, kv)| !kv.is_inherited()) {
+                    if let [KeyPart::Static(name)] = &key[..] {
+                        if let Some((_, value)) = replaced.iter().find(|(k, _)| k == name) {
+                            edits.push(replace_node(&kv.value, value.clone()));
+                        }
+                    }
+                }
+            },
+            d => edits.append(&mut service_config_edits(service, decl, Some(d.clone()), &replaced, verbose)?),
+        }
+    }
+
+    Ok(edits)
+}
+
+/// The edits of the file that declares `service`, `module` or one of its
+/// imports, made by `edits` from its root
+fn declaring_file_edits(
+    module: &Path,
+    service: &str,
+    edits: impl FnOnce(Root) -> Result<Vec<Edit>, Box<dyn Error>>
+) -> Result<FileEdits, Box<dyn Error>> {
+    let file = find_declaring_file(module, service)?;
+    let (content, ast) = parse_file(&file)?;
+    let edits = edits(ast.root()).map_err(|e| in_file(&file, &content, e))?;
+    Ok(FileEdits { file, content, edits })
+}
+
+/// The edits that set `options` in the `serviceConfig` of `service`
+pub fn service_edits(
+    module: &Path,
+    service: &str,
+    options: &[(String, String)],
+    catalog: &[HardeningOption],
+    verbose: bool
+) -> Result<FileEdits, Box<dyn Error>> {
+    check_option_values(catalog, options)?;
+    declaring_file_edits(module, service, |root| {
+        let decl = find_service_decl(root, service)?;
+        let cfg = decl.clone().project("serviceConfig")?;
+        service_config_edits(service, &decl, cfg, options, verbose)
+    })
+}
+
+/// The edits that set each option of `option_names` to
+/// `systemdPassthru.<service>.<option>` in the `serviceConfig` of `service`
+pub fn hook_edits(module: &Path, service: &str, option_names: &[String]) -> Result<FileEdits, Box<dyn Error>> {
+    declaring_file_edits(module, service, |root| systemd_hooks_edits(root, service, option_names))
+}
+
+/// Like `hook_edits`, on `content` rather than what `file`, which declares
+/// `service`, contains on disk
+pub fn content_hook_edits(
+    file: &Path,
+    content: String,
+    service: &str,
+    option_names: &[String]
+) -> Result<FileEdits, Box<dyn Error>> {
+    let located = |e| in_file(file, &content, e);
+    let ast = rnix::parse(&content).as_result().map_err(|e| located(e.into()))?;
+    let edits = systemd_hooks_edits(ast.root(), service, option_names).map_err(located)?;
+    Ok(FileEdits { file: file.to_path_buf(), content, edits })
+}
+
+/// The options of `option_names` the systemd of `nixpkgs` has, if given;
+/// they must all be in the catalog
+pub fn hookable_options(
+    catalog: &[HardeningOption],
+    option_names: &[String],
+    nixpkgs: Option<&str>
+) -> Result<Vec<String>, Box<dyn Error>> {
+    let systemd_version = nixpkgs.map(systemd_version).transpose()?;
+    let mut hooked = vec!();
+    for name in option_names {
+        let opt = find_option(catalog, name).ok_or(format!("{} is not in the option catalog", name))?;
+        if systemd_version.is_some_and(|v| !opt.is_supported_by(v)) {
+            eprintln!("{} is too recent for this nixpkgs' systemd, not hooking it", opt.name);
+        } else {
+            hooked.push(name.clone());
+        }
     }
+    Ok(hooked)
+}
 
-    apply_edits(edits, &mut text);
+/// What the edit commands do with the edited file
+pub enum EditOutput {
+    /// Print it on stdout
+    Print,
+    /// Rewrite it, keeping the original one if `backup`
+    Write { backup: bool },
+    /// Fail if it would change
+    Check,
+    /// Print a unified diff, with paths relative to the current directory
+    Diff,
+}
+
+/// Prints, writes or checks the edited file, which declares `service` but
+/// isn't always `module`; diffs are relative to `nixpkgs` if given, or else
+/// to the current directory
+fn output_edits(
+    module: &str,
+    service: &str,
+    nixpkgs: Option<&str>,
+    edits: &FileEdits,
+    output: &EditOutput
+) -> Result<(), Box<dyn Error>> {
+    if edits.file != Path::new(module) {
+        eprintln!("{} is declared in {}, editing it instead", service, edits.file.display());
+    }
 
-    print!("{}", text);
+    match output {
+        EditOutput::Print => print!("{}", edits.apply()?),
+        EditOutput::Write { backup } => edits.write(*backup)?,
+        EditOutput::Check => if edits.apply()? != edits.content {
+            Err(format!("{} would change", edits.file.display()))?
+        },
+        EditOutput::Diff => {
+            let path = relative_path(&edits.file, Path::new(nixpkgs.unwrap_or(".")))?;
+            print!("{}", unified_diff(&path, &edits.content, &edits.apply()?));
+        },
+    }
+    Ok(())
+}
 
+/// Checks the values given to catalog options are among the ones it knows.
+/// Only the boolean and string literals of the options with a few values
+/// are checked: lists and other expressions are passed through as is.
+fn check_option_values(catalog: &[HardeningOption], options: &[(String, String)]) -> Result<(), Box<dyn Error>> {
+    for (name, value) in options.iter() {
+        if let Some(opt) = find_option(catalog, name) {
+            let ast = rnix::parse(value).as_result()?;
+            let value = parse_cfg_value(ast.root().inner().ok_or("parse error")?)?;
+            let mut literal = &value;
+            while let CfgValue::Prioritized { inner, .. } = literal {
+                literal = inner;
+            }
+            let is_checked = opt.value_type != ValueType::List
+                && matches!(literal, CfgValue::Bool(_) | CfgValue::Str(_));
+            if is_checked && !opt.admits(&value) {
+                Err(format!("{} = {} isn't in the catalog's values for {}", name, value, name))?
+            }
+        }
+    }
     Ok(())
 }
 
+pub fn edit_systemd_service(
+    module: &str,
+    service: &str,
+    options: &str,
+    catalog: Option<&str>,
+    output: &EditOutput,
+    verbose: bool
+) -> Result<(), Box<dyn Error>> {
+    let options: Vec<(String, String)> = {
+        let content = fs::read_to_string(options)?;
+        serde_json::from_str(&content)?
+    };
+
+    let edits = service_edits(Path::new(module), service, &options, &load_catalog(catalog)?, verbose)?;
+
+    if verbose {
+        eprintln!("{}", edits.content);
+    }
+
+    output_edits(module, service, None, &edits, output)
+}
+
 fn add_passthru_arg(root: Root) -> Result<Vec<Edit>, Box<dyn Error>> {
-    let n = root.inner().and_then(Lambda::cast).ok_or("root isn't a function")?;
-    let n = n.arg().and_then(Pattern::cast).ok_or("root function's argument isn't a pattern")?;
+    let n = root.inner().and_then(Lambda::cast).ok_or_else(|| CodemodError::NonLambdaRoot(root.node().text_range()))?;
+    let n = n.arg().and_then(Pattern::cast).ok_or_else(|| CodemodError::NonLambdaRoot(n.node().text_range()))?;
 
     let already_defined = n.entries()
         .filter_map(|x| x.name())
@@ -179,7 +549,7 @@ fn systemd_hooks_edits(root: Root, service: &str, option_names: &[String]) -> Re
 
     edits.append(&mut add_passthru_arg(root.clone())?);
 
-    let decl = find_service_decl(root, &service)?;
+    let decl = find_service_decl(root, service)?;
     let cfg = decl.clone().project("serviceConfig")?;
 
     fn maybe_quote(name: &str) -> String {
@@ -194,35 +564,27 @@ fn systemd_hooks_edits(root: Root, service: &str, option_names: &[String]) -> Re
         .map(|name| (name.clone(), format!("systemdPassthru.{}.{}", maybe_quote(service), name)))
         .collect();
 
-    edits.append(&mut match cfg {
-        Some(DeclValue::Node(n)) =>
-            modify_attribute_set(n.value, &options)?,
-        Some(DeclValue::PartialAttr { node, prefix, entries }) =>
-            merge_decls(&node, &prefix, &entries, &options)?,
-        None =>
-            add_attribute_decl(&decl.value(), decl.prefix(), &options)?,
-    });
+    edits.append(&mut service_config_edits(service, &decl, cfg, &options, false)?);
 
     Ok(edits)
 }
 
-pub fn insert_systemd_hooks(module: &str, service: &str, option_names: &str) -> Result<(), Box<dyn Error>> {
-    let content = fs::read_to_string(&module)?;
-    let ast = rnix::parse(&content).as_result()?;
-
+pub fn insert_systemd_hooks(
+    module: &str,
+    service: &str,
+    option_names: &str,
+    catalog: Option<&str>,
+    nixpkgs: Option<&str>,
+    output: &EditOutput
+) -> Result<(), Box<dyn Error>> {
     let option_names: Vec<String> = {
-        let content = fs::read_to_string(&option_names)?;
+        let content = fs::read_to_string(option_names)?;
         serde_json::from_str(&content)?
     };
+    let hooked = hookable_options(&load_catalog(catalog)?, &option_names, nixpkgs)?;
+    let edits = hook_edits(Path::new(module), service, &hooked)?;
 
-    let edits = systemd_hooks_edits(ast.root(), service, &option_names)?;
-
-    let mut text = content.clone();
-    apply_edits(edits, &mut text);
-
-    print!("{}", text);
-
-    Ok(())
+    output_edits(module, service, nixpkgs, &edits, output)
 }
 
 #[cfg(test)]
@@ -230,6 +592,7 @@ mod edit_tests {
     use pretty_assertions::assert_eq;
 
     use super::*;
+    use crate::test_dir::TestDir;
 
     fn test_case(input: &str, output: &str) {
         let options: &[(String, String)] = &[
@@ -241,17 +604,10 @@ mod edit_tests {
         let decl = find_service_decl(ast.root(), "codemod").unwrap();
         let cfg = decl.clone().project("serviceConfig").unwrap();
 
-        let edits = match cfg {
-            Some(DeclValue::Node(n)) =>
-                modify_attribute_set(n.value, &options).unwrap(),
-            Some(DeclValue::PartialAttr { node, prefix, entries }) =>
-                merge_decls(&node, &prefix, &entries, &options).unwrap(),
-            None =>
-                add_attribute_decl(&decl.value(), decl.prefix(), &options).unwrap(),
-        };
+        let edits = service_config_edits("codemod", &decl, cfg, options, false).unwrap();
 
         let mut text = input.to_string();
-        apply_edits(edits, &mut text);
+        apply_edits(edits, &mut text).unwrap();
 
         assert_eq!(text, output);
     }
@@ -289,8 +645,8 @@ mod edit_tests {
         {}: {
           config.systemd.services.codemod.u = true;
           config.systemd.services.codemod.serviceConfig = {
-            b = true;
             a = false;
+            b = true;
             c = true;
           };
         }
@@ -329,8 +685,8 @@ mod edit_tests {
         {}: {
           config.systemd.services.codemod = {
             serviceConfig = {
-              b = true;
               a = false;
+              b = true;
               c = true;
             };
           };
@@ -338,6 +694,128 @@ mod edit_tests {
         ");
     }
 
+    #[test]
+    fn test_merge_keeps_comments() {
+        test_case("
+        {}: {
+          config.systemd.services.codemod.u = true;
+          # hardening
+          config.systemd.services.codemod.serviceConfig.a = true; # see #1234
+
+          # the paths
+          config.systemd.services.codemod.serviceConfig.b = [
+            \"/var/lib\"
+            # \"/var/cache\"
+          ];
+        }
+        ", "
+        {}: {
+          config.systemd.services.codemod.u = true;
+
+          config.systemd.services.codemod.serviceConfig = {
+            # hardening
+            a = false; # see #1234
+            # the paths
+            b = [
+              \"/var/lib\"
+              # \"/var/cache\"
+            ];
+            c = true;
+          };
+        }
+        ");
+    }
+
+    #[test]
+    fn test_tab_indent() {
+        test_case("
+{}: {
+	config.systemd.services.codemod = {
+		u = true;
+	};
+}
+", "
+{}: {
+	config.systemd.services.codemod = {
+		u = true;
+		serviceConfig = {
+			a = false;
+			c = true;
+		};
+	};
+}
+");
+    }
+
+    #[test]
+    fn test_expand_one_line_set() {
+        test_case("
+{}: {
+    config.systemd.services.codemod = { u = true; };
+    config.systemd.services.other = {
+        serviceConfig = { };
+    };
+}
+", "
+{}: {
+    config.systemd.services.codemod = {
+        u = true;
+        serviceConfig = {
+            a = false;
+            c = true;
+        };
+    };
+    config.systemd.services.other = {
+        serviceConfig = { };
+    };
+}
+");
+        test_case("
+{}: {
+  config.systemd.services.codemod = { serviceConfig.a = true; wantedBy = [ ]; };
+  config.systemd.services.other = { wantedBy = [ ]; serviceConfig.b = true; };
+}
+", "
+{}: {
+  config.systemd.services.codemod = {
+    wantedBy = [ ];
+    serviceConfig = {
+      a = false;
+      c = true;
+    };
+  };
+  config.systemd.services.other = { wantedBy = [ ]; serviceConfig.b = true; };
+}
+");
+        test_case("
+{}: {
+  config.systemd.services.codemod = { wantedBy = [ ]; serviceConfig.a = true; };
+}
+", "
+{}: {
+  config.systemd.services.codemod = {
+    wantedBy = [ ];
+    serviceConfig = {
+      a = false;
+      c = true;
+    };
+  };
+}
+");
+        test_case("
+{}: {
+  config.systemd.services.codemod.serviceConfig = {};
+}
+", "
+{}: {
+  config.systemd.services.codemod.serviceConfig = {
+    a = false;
+    c = true;
+  };
+}
+");
+    }
+
     #[test]
     fn test_modify_entries() {
         test_case("
@@ -352,50 +830,735 @@ mod edit_tests {
         {}:
         {
             config.systemd.services.codemod.serviceConfig = {
-                b = true;
                 a = false;
+                b = true;
                 c = true;
             };
         }
         ");
     }
-}
-
-#[cfg(test)]
-mod hooks_test {
-    use pretty_assertions::assert_eq;
-
-    use super::*;
-
-    fn base_test_case(service: &str, input: &str, output: &str) {
-        let option_names = &["a".to_string(), "c".to_string()];
-        let ast = rnix::parse(input).as_result().unwrap();
 
-        let edits = systemd_hooks_edits(ast.root(), service, option_names).unwrap();
-        let mut text = input.to_string();
-        apply_edits(edits, &mut text);
+    #[test]
+    fn test_modify_keeps_comments() {
+        test_case("
+        {}: {
+          config.systemd.services.codemod.serviceConfig = {
+            # the default
+            a = true; # see #1234
+            b = {
+              x = 1;
+            };
+          };
+        }
+        ", "
+        {}: {
+          config.systemd.services.codemod.serviceConfig = {
+            # the default
+            a = false; # see #1234
+            b = {
+              x = 1;
+            };
+            c = true;
+          };
+        }
+        ");
+    }
 
-        assert_eq!(text, output);
+    #[test]
+    fn test_mk_merge_value() {
+        test_case("
+        {}: {
+          config.systemd.services.codemod.serviceConfig = lib.mkMerge [
+            { u = true; }
+            (lib.mkIf cfg.b { a = true; })
+          ];
+        }
+        ", "
+        {}: {
+          config.systemd.services.codemod.serviceConfig = lib.mkMerge [
+            {
+              u = true;
+              a = false;
+              c = true;
+            }
+            (lib.mkIf cfg.b { })
+          ];
+        }
+        ");
     }
-    
-    fn test_case(input: &str, output: &str) {
-        base_test_case("codemod", input, output)
+
+    #[test]
+    fn test_mk_merge_commented_entry() {
+        test_case("
+        {}: {
+          config.systemd.services.codemod.serviceConfig = lib.mkMerge [
+            {
+              u = true;
+            }
+            (lib.mkIf cfg.b {
+              # why a is set
+              a = true; # really
+              v = true;
+            })
+          ];
+        }
+        ", "
+        {}: {
+          config.systemd.services.codemod.serviceConfig = lib.mkMerge [
+            {
+              u = true;
+              a = false;
+              c = true;
+            }
+            (lib.mkIf cfg.b {
+              v = true;
+            })
+          ];
+        }
+        ");
     }
 
     #[test]
-    fn test_systemd_hooks() {
+    fn test_mk_merge_shared_element() {
         test_case("
-        { pkgs, ... }: {
-          config.systemd.services.codemod.serviceConfig = {
+        {}:
+        let
+          common = {
             a = true;
-            b = true;
           };
+        in {
+          config.systemd.services.codemod.serviceConfig = lib.mkMerge [
+            {
+              u = true;
+            }
+            common
+          ];
+          config.systemd.services.other.serviceConfig = common;
         }
         ", "
-        { systemdPassthru, pkgs, ... }: {
-          config.systemd.services.codemod.serviceConfig = {
-            b = true;
-            a = systemdPassthru.codemod.a;
+        {}:
+        let
+          common = {
+            a = true;
+          };
+        in {
+          config.systemd.services.codemod.serviceConfig = lib.mkMerge [
+            {
+              u = true;
+              a = false;
+              c = true;
+            }
+            (common // {
+              a = false;
+            })
+          ];
+          config.systemd.services.other.serviceConfig = common;
+        }
+        ");
+    }
+
+    #[test]
+    fn test_set_and_entries() {
+        test_case("
+        {}: {
+          config.systemd.services.codemod.serviceConfig = { b = true; };
+          config.systemd.services.codemod.serviceConfig.a = true;
+        }
+        ", "
+        {}: {
+          config.systemd.services.codemod.serviceConfig = {
+            b = true;
+            a = false;
+            c = true;
+          };
+        }
+        ");
+    }
+
+    #[test]
+    fn test_mk_merge_modify() {
+        test_case("
+        {}: {
+          config = mkMerge [
+            (mkIf cfg.enable {
+              systemd.services.codemod.u = true;
+            })
+            {
+              systemd.services.codemod.serviceConfig = {
+                a = true;
+                b = true;
+              };
+            }
+          ];
+        }
+        ", "
+        {}: {
+          config = mkMerge [
+            (mkIf cfg.enable {
+              systemd.services.codemod.u = true;
+            })
+            {
+              systemd.services.codemod.serviceConfig = {
+                a = false;
+                b = true;
+                c = true;
+              };
+            }
+          ];
+        }
+        ");
+    }
+
+    #[test]
+    fn test_mk_merge_split_entries() {
+        test_case("
+        {}: {
+          config = mkIf cfg.enable (mkMerge [
+            {
+              systemd.services.codemod.serviceConfig.a = true;
+            }
+            (mkIf cfg.b {
+              systemd.services.codemod = {
+                serviceConfig = {
+                  b = true;
+                };
+              };
+            })
+          ]);
+        }
+        ", "
+        {}: {
+          config = mkIf cfg.enable (mkMerge [
+            {
+              systemd.services.codemod.serviceConfig = {
+                a = false;
+                c = true;
+              };
+            }
+            (mkIf cfg.b {
+              systemd.services.codemod = {
+                serviceConfig = {
+                  b = true;
+                };
+              };
+            })
+          ]);
+        }
+        ");
+    }
+
+    #[test]
+    fn test_mk_merge_guarded_entries() {
+        test_case("
+        {}: {
+          config = mkMerge [
+            (mkIf cfg.a {
+              systemd.services.codemod.serviceConfig.a = true;
+            })
+            (lib.optionalAttrs cfg.b {
+              systemd.services.codemod = {
+                serviceConfig = {
+                  b = true;
+                };
+              };
+            })
+          ];
+        }
+        ", "
+        {}: {
+          config = mkMerge [
+            (mkIf cfg.a {
+              systemd.services.codemod.serviceConfig.a = false;
+            })
+            (lib.optionalAttrs cfg.b {
+              systemd.services.codemod = {
+                serviceConfig = {
+                  b = true;
+                  c = true;
+                };
+              };
+            })
+          ];
+        }
+        ");
+    }
+
+    #[test]
+    fn test_inherited_binding() {
+        test_case("
+        {}:
+        let
+          serviceConfig = {
+            a = true;
+            b = true;
+          };
+        in {
+          config.systemd.services.codemod = {
+            inherit serviceConfig;
+          };
+        }
+        ", "
+        {}:
+        let
+          serviceConfig = {
+            a = false;
+            b = true;
+            c = true;
+          };
+        in {
+          config.systemd.services.codemod = {
+            inherit serviceConfig;
+          };
+        }
+        ");
+    }
+
+    #[test]
+    fn test_inherit_from() {
+        test_case("
+        {}:
+        let
+          common = {
+            serviceConfig = {
+              b = true;
+            };
+          };
+        in {
+          config.systemd.services.codemod = {
+            inherit (common) serviceConfig;
+          };
+        }
+        ", "
+        {}:
+        let
+          common = {
+            serviceConfig = {
+              b = true;
+              a = false;
+              c = true;
+            };
+          };
+        in {
+          config.systemd.services.codemod = {
+            inherit (common) serviceConfig;
+          };
+        }
+        ");
+    }
+
+    #[test]
+    fn test_split_selected_binding() {
+        test_case("
+        {}:
+        let
+          common = {
+            serviceConfig = {
+              b = true;
+            };
+          };
+        in {
+          config.systemd.services.codemod = {
+            inherit (common) serviceConfig;
+          };
+          config.systemd.services.other.serviceConfig = common.serviceConfig;
+        }
+        ", "
+        {}:
+        let
+          common = {
+            serviceConfig = {
+              b = true;
+            };
+          };
+        in {
+          config.systemd.services.codemod = {
+            serviceConfig = common.serviceConfig // {
+              a = false;
+              c = true;
+            };
+          };
+          config.systemd.services.other.serviceConfig = common.serviceConfig;
+        }
+        ");
+    }
+
+    #[test]
+    fn test_split_shared_binding() {
+        test_case("
+        {}:
+        let
+          common = {
+            a = true;
+            b = true;
+          };
+        in {
+          config.systemd.services.other.serviceConfig = common;
+          config.systemd.services.codemod = {
+            serviceConfig = common;
+          };
+        }
+        ", "
+        {}:
+        let
+          common = {
+            a = true;
+            b = true;
+          };
+        in {
+          config.systemd.services.other.serviceConfig = common;
+          config.systemd.services.codemod = {
+            serviceConfig = common // {
+              a = false;
+              c = true;
+            };
+          };
+        }
+        ");
+    }
+
+    #[test]
+    fn test_split_shared_inherit() {
+        test_case("
+        {}:
+        let
+          serviceConfig = {
+            b = true;
+          };
+        in {
+          config.systemd.services.other = {
+            inherit serviceConfig;
+          };
+          config.systemd.services.codemod = {
+            inherit serviceConfig;
+            u = true;
+          };
+        }
+        ", "
+        {}:
+        let
+          serviceConfig = {
+            b = true;
+          };
+        in {
+          config.systemd.services.other = {
+            inherit serviceConfig;
+          };
+          config.systemd.services.codemod = {
+            u = true;
+            serviceConfig = serviceConfig // {
+              a = false;
+              c = true;
+            };
+          };
+        }
+        ");
+    }
+
+    #[test]
+    fn test_update_operator() {
+        test_case("
+        {}:
+        let
+          defaults = {
+            a = true;
+          };
+        in {
+          config.systemd.services.codemod = defaults // {
+            serviceConfig = defaults // {
+              b = true;
+            } // optionalAttrs cfg.x {
+              u = true;
+            };
+          };
+        }
+        ", "
+        {}:
+        let
+          defaults = {
+            a = true;
+          };
+        in {
+          config.systemd.services.codemod = defaults // {
+            serviceConfig = defaults // {
+              b = true;
+              a = false;
+              c = true;
+            } // optionalAttrs cfg.x {
+              u = true;
+            };
+          };
+        }
+        ");
+    }
+
+    #[test]
+    fn test_update_unreduced_operand() {
+        test_case("
+        {}: {
+          config.systemd.services.codemod.serviceConfig = {
+            a = true;
+          } // cfg.serviceOverrides;
+        }
+        ", "
+        {}: {
+          config.systemd.services.codemod.serviceConfig = {
+            a = true;
+          } // cfg.serviceOverrides // {
+            a = false;
+            c = true;
+          };
+        }
+        ");
+    }
+
+    #[test]
+    fn test_quoted_key() {
+        test_case(r#"
+        {}: {
+          config.systemd.services.codemod.serviceConfig = {
+            "a" = true;
+            ${"b"} = true;
+          };
+        }
+        "#, r#"
+        {}: {
+          config.systemd.services.codemod.serviceConfig = {
+            "a" = false;
+            ${"b"} = true;
+            c = true;
+          };
+        }
+        "#);
+    }
+
+    #[test]
+    fn test_update_overridden_entry() {
+        test_case("
+        {}: {
+          config.systemd.services.codemod.serviceConfig = {
+            u = true;
+          } // optionalAttrs cfg.x {
+            a = true;
+          };
+        }
+        ", "
+        {}: {
+          config.systemd.services.codemod.serviceConfig = {
+            u = true;
+          } // optionalAttrs cfg.x {
+            a = true;
+          } // {
+            a = false;
+            c = true;
+          };
+        }
+        ");
+    }
+
+    #[test]
+    fn test_update_operator_add() {
+        test_case("
+        {}: {
+          config.systemd.services.codemod = { u = true; } // {
+            v = true;
+          };
+        }
+        ", "
+        {}: {
+          config.systemd.services.codemod = { u = true; } // {
+            v = true;
+            serviceConfig = {
+              a = false;
+              c = true;
+            };
+          };
+        }
+        ");
+    }
+
+    #[test]
+    fn test_qualified_combinators() {
+        test_case("
+        { lib, ... }: {
+          config = lib.mkIf cfg.enable (lib.modules.mkMerge [
+            (lib.optionalAttrs cfg.b {
+              systemd.services.codemod = lib.mkDefault {
+                u = true;
+              };
+            })
+          ]);
+        }
+        ", "
+        { lib, ... }: {
+          config = lib.mkIf cfg.enable (lib.modules.mkMerge [
+            (lib.optionalAttrs cfg.b {
+              systemd.services.codemod = lib.mkDefault {
+                u = true;
+                serviceConfig = {
+                  a = false;
+                  c = true;
+                };
+              };
+            })
+          ]);
+        }
+        ");
+    }
+
+    #[test]
+    fn test_mk_merge_add() {
+        test_case("
+        {}: {
+          config = mkMerge [
+            {
+              systemd.services.codemod.u = true;
+            }
+            {
+              systemd.services.codemod.v = true;
+            }
+          ];
+        }
+        ", "
+        {}: {
+          config = mkMerge [
+            {
+              systemd.services.codemod.u = true;
+              systemd.services.codemod.serviceConfig = {
+                a = false;
+                c = true;
+              };
+            }
+            {
+              systemd.services.codemod.v = true;
+            }
+          ];
+        }
+        ");
+    }
+
+    #[test]
+    fn test_check_option_values() {
+        let catalog = builtin_catalog();
+        let check = |name: &str, value: &str| check_option_values(&catalog, &[(name.to_string(), value.to_string())]);
+
+        assert!(check("ProtectSystem", "\"strict\"").is_ok());
+        assert!(check("ProtectSystem", "lib.mkForce true").is_ok());
+        assert!(check("ProtectSystem", "\"yes\"").is_err());
+        assert!(check("SystemCallFilter", "[ \"@system-service\" ]").is_ok());
+        assert!(check("Foo", "42").is_ok());
+        assert!(check("PrivateTmp", "\"yes\"").is_err());
+        assert!(check("PrivateTmp", "cfg.privateTmp").is_ok());
+        assert!(check("RestrictAddressFamilies", "[ \"AF_UNIX\" \"AF_PACKET\" ]").is_ok());
+    }
+
+    #[test]
+    fn test_unsupported_config() {
+        let input = "
+        { config, lib, ... }: {
+          config.systemd.services.codemod.serviceConfig = lib.mapAttrs f cfg.settings;
+        }
+        ";
+        let ast = rnix::parse(input).as_result().unwrap();
+        let decl = find_service_decl(ast.root(), "codemod").unwrap();
+        let cfg = decl.clone().project("serviceConfig").unwrap();
+
+        let e = service_config_edits("codemod", &decl, cfg, &[("a".to_string(), "false".to_string())], false).unwrap_err();
+        assert_eq!(crate::error::error_category(e.as_ref()), "function application");
+    }
+
+    #[test]
+    fn test_overlapping_edits() {
+        let mut text = "abcdef".to_string();
+        let edits = vec![
+            Edit { start: 1, end: 3, replace: "x".to_string() },
+            Edit { start: 2, end: 4, replace: "y".to_string() },
+        ];
+        let e = apply_edits(edits, &mut text).unwrap_err();
+        assert_eq!(crate::error::error_category(e.as_ref()), "overlapping edits");
+        assert_eq!(text, "abcdef");
+    }
+
+    /// A module declaring `codemod`, and the options file that sets
+    /// `PrivateTmp`, in `dir`
+    fn output_case(dir: &TestDir) -> (String, String, &'static str) {
+        let input = "\
+{ config, lib, ... }: {
+  config.systemd.services.codemod.serviceConfig = {
+    PrivateTmp = false;
+  };
+}
+";
+        let module = dir.write("module.nix", input);
+        let options = dir.write("options.json", r#"[["PrivateTmp", "true"]]"#);
+        (module.display().to_string(), options.display().to_string(), input)
+    }
+
+    #[test]
+    fn test_output_check() {
+        let dir = TestDir::new("edit-check");
+        let (module, options, input) = output_case(&dir);
+
+        let e = edit_systemd_service(&module, "codemod", &options, None, &EditOutput::Check, false).unwrap_err();
+        assert_eq!(e.to_string(), format!("{} would change", module));
+        assert_eq!(fs::read_to_string(&module).unwrap(), input);
+
+        let unchanged = dir.write("options.json", r#"[["PrivateTmp", "false"]]"#);
+        edit_systemd_service(&module, "codemod", &unchanged.display().to_string(), None, &EditOutput::Check, false).unwrap();
+    }
+
+    #[test]
+    fn test_output_write() {
+        let dir = TestDir::new("edit-write");
+        let (module, options, input) = output_case(&dir);
+
+        edit_systemd_service(&module, "codemod", &options, None, &EditOutput::Write { backup: true }, false).unwrap();
+        assert_eq!(fs::read_to_string(&module).unwrap(), input.replace("false", "true"));
+        assert_eq!(fs::read_to_string(format!("{}.orig", module)).unwrap(), input);
+
+        // the temporary file the content went through is renamed to the module
+        let mut files: Vec<String> = fs::read_dir(dir.path()).unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().to_string())
+            .collect();
+        files.sort();
+        assert_eq!(files, ["module.nix", "module.nix.orig", "options.json"]);
+    }
+}
+
+#[cfg(test)]
+mod hooks_test {
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+
+    fn base_test_case(service: &str, input: &str, output: &str) {
+        let option_names = &["a".to_string(), "c".to_string()];
+        let ast = rnix::parse(input).as_result().unwrap();
+
+        let edits = systemd_hooks_edits(ast.root(), service, option_names).unwrap();
+        let mut text = input.to_string();
+        apply_edits(edits, &mut text).unwrap();
+
+        assert_eq!(text, output);
+    }
+    
+    fn test_case(input: &str, output: &str) {
+        base_test_case("codemod", input, output)
+    }
+
+    #[test]
+    fn test_systemd_hooks() {
+        test_case("
+        { pkgs, ... }: {
+          config.systemd.services.codemod.serviceConfig = {
+            a = true;
+            b = true;
+          };
+        }
+        ", "
+        { systemdPassthru, pkgs, ... }: {
+          config.systemd.services.codemod.serviceConfig = {
+            a = systemdPassthru.codemod.a;
+            b = true;
             c = systemdPassthru.codemod.c;
           };
         }
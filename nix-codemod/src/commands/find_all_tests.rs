@@ -1,15 +1,14 @@
 
-use std::fs;
+use std::path::Path;
 use std::error::Error;
 
 use serde::Serialize;
 
-use serde_json;
-
 use rnix::types::*;
 use rnix::SyntaxNode;
 
 use crate::walkers::*;
+use crate::source::parse_file;
 
 fn find_test_file(n: SyntaxNode) -> Result<String, Box<dyn Error>> {
     let app_ext = Apply::cast(n).ok_or("unexpected file structure")?;
@@ -33,14 +32,16 @@ fn find_test_file(n: SyntaxNode) -> Result<String, Box<dyn Error>> {
 }
 
 #[derive(Serialize)]
-struct Output {
+pub struct AllTests {
+    /// The name of each test, and its file
     paths: Vec<(String, String)>,
+    /// The tests whose file we couldn't find
     failures: Vec<String>,
 }
 
-pub fn find_all_tests(all_tests: &str) -> Result<(), Box<dyn Error>> {
-    let content = fs::read_to_string(&all_tests)?;
-    let ast = rnix::parse(&content).as_result()?;
+/// The tests `all-tests.nix` defines
+pub fn all_tests(all_tests: &Path) -> Result<AllTests, Box<dyn Error>> {
+    let (_, ast) = parse_file(all_tests)?;
 
     let root_fn = ast.root().inner().and_then(Lambda::cast).ok_or("root isn't a function")?;
     let val = go_right_value(root_fn.body().ok_or("parse error")?)?;
@@ -63,7 +64,11 @@ pub fn find_all_tests(all_tests: &str) -> Result<(), Box<dyn Error>> {
         }
     }
 
-    println!("{}", serde_json::to_string(&Output { paths, failures })?);
+    Ok(AllTests { paths, failures })
+}
+
+pub fn find_all_tests(all_tests: &str) -> Result<(), Box<dyn Error>> {
+    println!("{}", serde_json::to_string(&self::all_tests(Path::new(all_tests))?)?);
 
     Ok(())
 }
@@ -0,0 +1,150 @@
+
+use std::fs;
+use std::error::Error;
+use std::path::{Path, PathBuf};
+use std::collections::BTreeMap;
+
+use serde::Deserialize;
+
+use crate::catalog::*;
+use crate::edit::{Edit, FileEdits};
+use crate::error::catch_panic;
+use crate::imports::find_declaring_file;
+use crate::diff::{relative_path, unified_diff};
+use super::{EditOutput, hookable_options, content_hook_edits};
+
+/// A service of the `collect-tests` output, which has more fields
+#[derive(Deserialize)]
+struct Hooks {
+    module: String,
+    /// The blank options, the ones to hook
+    fields: Vec<String>,
+}
+
+/// The files hooking `services` changes, with their original and their new
+/// content. Several services can be declared in the same file, so the edits
+/// of each one apply to the content the previous ones left.
+fn hooked_files(
+    nixpkgs: &str,
+    services: &BTreeMap<String, Hooks>,
+    catalog: &[HardeningOption]
+) -> (BTreeMap<PathBuf, (String, String)>, usize) {
+    let mut files: BTreeMap<PathBuf, (String, String)> = BTreeMap::new();
+    let mut failures = 0;
+
+    for (service, Hooks { module, fields }) in services.iter() {
+        let hooked = catch_panic(|| {
+            let hooked = hookable_options(catalog, fields, Some(nixpkgs))?;
+            let file = find_declaring_file(Path::new(module), service)?;
+            let content = match files.get(&file) {
+                Some((_, content)) => content.clone(),
+                None => fs::read_to_string(&file)?,
+            };
+            let edits = content_hook_edits(&file, content, service, &hooked)?;
+            Ok((edits.apply()?, edits))
+        });
+
+        match hooked {
+            Ok((text, FileEdits { file, content, .. })) => {
+                files.entry(file).or_insert_with(|| (content, String::new())).1 = text;
+            },
+            Err(e) => {
+                eprintln!("{} ({}): {}", service, module, e);
+                failures += 1;
+            },
+        }
+    }
+
+    (files, failures)
+}
+
+/// Hooks every service of `collected_tests`, the output of `collect-tests`.
+/// Printing them prints the diff, with paths relative to `nixpkgs`.
+pub fn hook_modules(
+    nixpkgs: &str,
+    collected_tests: &str,
+    catalog: Option<&str>,
+    output: &EditOutput
+) -> Result<(), Box<dyn Error>> {
+    let services: BTreeMap<String, Hooks> = serde_json::from_str(&fs::read_to_string(collected_tests)?)?;
+    let catalog = load_catalog(catalog)?;
+    let (files, failures) = hooked_files(nixpkgs, &services, &catalog);
+    let changed: Vec<_> = files.into_iter()
+        .filter(|(_, (original, hooked))| original != hooked)
+        .collect();
+
+    match output {
+        EditOutput::Print | EditOutput::Diff => for (file, (original, hooked)) in changed.iter() {
+            print!("{}", unified_diff(&relative_path(file, Path::new(nixpkgs))?, original, hooked));
+        },
+        EditOutput::Write { backup } => for (file, (original, hooked)) in changed.iter() {
+            let edit = Edit { start: 0, end: original.len(), replace: hooked.clone() };
+            FileEdits { file: file.clone(), content: original.clone(), edits: vec![edit] }.write(*backup)?;
+        },
+        EditOutput::Check => if let Some((file, _)) = changed.first() {
+            Err(format!("{} and {} other files would change", file.display(), changed.len() - 1))?
+        },
+    }
+
+    eprintln!("{} services hooked in {} files, {} failed", services.len() - failures, changed.len(), failures);
+    Ok(())
+}
+
+#[cfg(test)]
+mod hook_modules_tests {
+    use pretty_assertions::assert_eq;
+
+    use crate::test_dir::TestDir;
+    use super::*;
+
+    #[test]
+    fn test_hooked_files() {
+        let dir = TestDir::new("hook-modules");
+        dir.write("pkgs/os-specific/linux/systemd/default.nix", "{ }: { version = \"254.6\"; }");
+        let module = dir.write("module.nix", "\
+{ config, ... }: {
+  config.systemd.services.foo = {
+    wantedBy = [ ];
+  };
+  config.systemd.services.bar = {
+    wantedBy = [ ];
+  };
+}
+");
+
+        let hooks = |fields: &[&str]| Hooks {
+            module: module.display().to_string(),
+            fields: fields.iter().map(|f| f.to_string()).collect(),
+        };
+        let services = BTreeMap::from([
+            ("foo".to_string(), hooks(&["PrivateTmp"])),
+            ("bar".to_string(), hooks(&["PrivateTmp"])),
+            ("baz".to_string(), hooks(&["PrivateTmp"])),
+        ]);
+
+        let catalog = load_catalog(None).unwrap();
+        let (files, failures) = hooked_files(dir.path().to_str().unwrap(), &services, &catalog);
+        assert_eq!(failures, 1);
+        let (original, hooked) = &files[&module];
+        assert_eq!(unified_diff(&relative_path(&module, dir.path()).unwrap(), original, hooked), "\
+--- a/module.nix
++++ b/module.nix
+@@ -1,8 +1,14 @@
+-{ config, ... }: {
++{ systemdPassthru, config, ... }: {
+   config.systemd.services.foo = {
+     wantedBy = [ ];
++    serviceConfig = {
++      PrivateTmp = systemdPassthru.foo.PrivateTmp;
++    };
+   };
+   config.systemd.services.bar = {
+     wantedBy = [ ];
++    serviceConfig = {
++      PrivateTmp = systemdPassthru.bar.PrivateTmp;
++    };
+   };
+ }
+");
+    }
+}
@@ -1,14 +1,14 @@
 
-use std::fs;
+use std::path::Path;
 use std::error::Error;
 
 use rnix::types::*;
 
 use crate::walkers::*;
+use crate::source::parse_file;
 
 pub fn is_test_well_formed(test: &str) -> Result<(), Box<dyn Error>> {
-    let content = fs::read_to_string(&test)?;
-    let ast = rnix::parse(&content).as_result()?;
+    let (_, ast) = parse_file(Path::new(test))?;
     
     let val = go_right_value(ast.root().inner().ok_or("parse error")?)?;
 
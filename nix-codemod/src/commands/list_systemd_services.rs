@@ -1,22 +1,17 @@
 
-use std::fs;
 use std::error::Error;
-use std::iter;
-
-use clap::Parser;
-use clap::Subcommand;
-
-use serde_json;
+use std::path::Path;
 
 use rnix::types::*;
-use rnix::SyntaxNode;
+use serde::{Serialize, Deserialize};
 
 use crate::walkers::*;
-use crate::edit::*;
+use crate::imports::module_files;
+use crate::location::{Location, Position, in_file};
+use crate::source::parse_file;
 
-fn find_systemd_services(root: Root) -> Result<Vec<String>, Box<dyn Error>> {
-    let x = root.inner().and_then(Lambda::cast).ok_or("root isn't a function")?;
-    let x = go_right_value(x.body().ok_or("parse error")?)?;
+fn find_systemd_services(root: Root) -> Result<Vec<KeyPart>, Box<dyn Error>> {
+    let x = root_body(&root)?;
     let x = decl_value(
         &[  "config".to_string(),
             "systemd".to_string(),
@@ -24,10 +19,9 @@ fn find_systemd_services(root: Root) -> Result<Vec<String>, Box<dyn Error>> {
         x)?;
     
     match x {
-        Some(DeclValue::Node(_)) => Err("Couldn't reduce")?,
-        Some(DeclValue::PartialAttr { entries, .. }) => {
-            let mut keys = entries.iter()
-                .map(|(attr_name, _)| attr_name.first().unwrap().clone())
+        Some(x) => {
+            let mut keys = x.reduce_entries()?.into_iter()
+                .map(|(attr_name, _)| attr_name[0].clone())
                 .collect::<Vec<_>>();
             keys.sort();
             keys.dedup();
@@ -37,58 +31,214 @@ fn find_systemd_services(root: Root) -> Result<Vec<String>, Box<dyn Error>> {
     }
 }
 
-fn find_service_decl(root: Root, service: &str) -> Result<DeclValue, Box<dyn Error>> {
-    let x = root.inner().and_then(Lambda::cast).ok_or("root isn't a function")?;
-    let x = go_right_value(x.body().ok_or("parse error")?)?;
-    decl_value(
-        &[  "config".to_string(),
-            "systemd".to_string(),
-            "services".to_string(),
-            service.to_string()],
-        x)?.ok_or(format!("config.systemd.services.{} is not declared", service).into())
+pub type ServicePositions = Vec<(String, Position)>;
+
+/// The services we found but can't reduce, and why
+pub type ServiceErrors = Vec<(String, Box<dyn Error>)>;
+
+/// Where `service` is declared, if we can reduce its config
+fn service_position(root: Root, service: &str) -> Result<Position, Box<dyn Error>> {
+    let decl = find_service_decl(root, service)?;
+    let position = decl.position();
+    if let Some(cfg) = decl.project("serviceConfig")? {
+        cfg.reduce_entries()?;
+    }
+    Ok(position)
 }
 
-pub fn list_systemd_services(module: &str, verbose: bool) -> Result<(), Box<dyn Error>> {
-    let content = fs::read_to_string(&module)?;
-    let ast = rnix::parse(&content).as_result()?;
-    
-    let declared_services: Vec<String> = find_systemd_services(ast.root())?
+/// The services whose config we can reduce, along with the line and column
+/// they're declared at, the names we can't compute, and the services we
+/// can't reduce
+pub fn declared_services(root: Root) -> Result<(ServicePositions, Vec<KeyPart>, ServiceErrors), Box<dyn Error>> {
+    let (static_names, dynamic_names): (Vec<KeyPart>, Vec<KeyPart>) = find_systemd_services(root.clone())?
         .into_iter()
-        .filter_map(|name| -> Option<String> {
-            let decl = find_service_decl(ast.root(), &name).ok()?;
-            let cfg = decl.clone().project("serviceConfig").ok()?;
-            match cfg {
-                Some(cfg) => if cfg.entries().ok()?.is_some() { Some(name) } else { None },
-                None => {
-                    let inherited: Vec<String> = AttrSet::cast(decl.value().clone()).ok_or("parse error").ok()?.inherits()
-                        .map(|inherit| inherit.idents().map(|ident| ident.as_str().to_string()))
-                        .flatten()
-                        .collect();
-
-                    if inherited.contains(&"serviceConfig".to_string()) {
-                        None
-                    } else {
-                        Some(name)
-                    }
-                }
+        .partition(|name| name.as_static().is_some());
+
+    let mut declared_services: ServicePositions = vec!();
+    let mut errors: ServiceErrors = vec!();
+    for name in static_names.iter().filter_map(KeyPart::as_static) {
+        match service_position(root.clone(), name) {
+            Ok(position) => declared_services.push((name.to_string(), position)),
+            Err(e) => errors.push((name.to_string(), e)),
+        }
+    }
+
+    Ok((declared_services, dynamic_names, errors))
+}
+
+/// A service, and where it is declared
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ServiceDecl {
+    pub service: String,
+    pub location: Location,
+}
+
+/// The services a module declares, the names we can't compute, and the
+/// services we can't reduce
+pub type ModuleServices = (Vec<ServiceDecl>, Vec<KeyPart>, ServiceErrors);
+
+/// The services declared by `module` and the files it imports, with the
+/// errors located in their file
+pub fn module_services(module: &Path) -> Result<ModuleServices, Box<dyn Error>> {
+    let mut services: Vec<ServiceDecl> = vec!();
+    let mut dynamic_names: Vec<KeyPart> = vec!();
+    let mut errors: ServiceErrors = vec!();
+
+    for file in module_files(module)? {
+        let (content, ast) = parse_file(&file)?;
+        let found = match declared_services(ast.root()).map_err(|e| in_file(&file, &content, e)) {
+            Ok(found) => found,
+            Err(e) if file != module => {
+                eprintln!("{}", e);
+                continue
+            },
+            Err(e) => Err(e)?,
+        };
+
+        for (service, position) in found.0 {
+            if !services.iter().any(|s| s.service == service) {
+                services.push(ServiceDecl { service, location: Location::new(&file, position) });
             }
-        })
-        .collect();
+        }
+        dynamic_names.extend(found.1);
+        errors.extend(found.2.into_iter().map(|(service, e)| (service, in_file(&file, &content, e))));
+    }
+
+    Ok((services, dynamic_names, errors))
+}
+
+pub fn list_systemd_services(module: &str, verbose: bool) -> Result<(), Box<dyn Error>> {
+    let (services, dynamic_names, errors) = module_services(Path::new(module))?;
 
     if verbose {
-        if declared_services.len() == 0 {
+        for name in dynamic_names.iter() {
+            println!("dynamic service name: {}", name);
+        }
+        for (service, e) in errors.iter() {
+            println!("can't reduce {}: {}", service, e);
+        }
+
+        if services.is_empty() {
             println!("No systemd service");
             return Ok(())
         }
 
         println!("This file declares");
-        for service in declared_services.iter() {
-            println!(" * {}", service);
+        for ServiceDecl { service, location } in services.iter() {
+            if Path::new(&location.file) == Path::new(module) {
+                println!(" * {} (line {})", service, location.line);
+            } else {
+                println!(" * {} (in {})", service, location);
+            }
         }
     } else {
-        println!("{}", serde_json::to_string(&declared_services)?);
+        for name in dynamic_names.iter() {
+            eprintln!("dynamic service name: {}", name);
+        }
+        for (service, e) in errors.iter() {
+            eprintln!("can't reduce {}: {}", service, e);
+        }
+
+        println!("{}", serde_json::to_string(&services)?);
     }
 
     Ok(())
 }
 
+#[cfg(test)]
+mod list_tests {
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+
+    fn test_case(input: &str, services: &[&str]) {
+        let ast = rnix::parse(input).as_result().unwrap();
+        let services: Vec<KeyPart> = services.iter()
+            .map(|s| match s.strip_prefix('$') {
+                Some(s) => KeyPart::Dynamic(s.to_string()),
+                None => KeyPart::Static(s.to_string()),
+            })
+            .collect();
+        assert_eq!(find_systemd_services(ast.root()).unwrap(), services);
+    }
+
+    #[test]
+    fn test_mk_merge() {
+        test_case("
+        { config, lib, ... }: {
+          config = mkMerge [
+            (mkIf cfg.enable {
+              systemd.services.b.serviceConfig.a = true;
+            })
+            {
+              systemd.services.a = { };
+              systemd.services.b.wantedBy = [ ];
+            }
+          ];
+        }
+        ", &["a", "b"]);
+
+        test_case("
+        { config, lib, ... }: {
+          config.systemd.services = lib.mkMerge [
+            { a.serviceConfig.PrivateTmp = true; }
+            (lib.mkIf cfg.enable {
+              b = { };
+            })
+          ];
+        }
+        ", &["a", "b"]);
+    }
+
+    #[test]
+    fn test_dynamic_keys() {
+        test_case("
+        { config, lib, ... }:
+        let
+          name = \"foo\";
+          inherit (cfg) user;
+        in {
+          config.systemd.services = {
+            \"${name}-worker\" = { };
+            ${name} = { };
+            \"${cfg.name}-worker\" = { };
+            ${user} = { };
+          };
+        }
+        ", &["foo", "foo-worker", "$\"${cfg.name}-worker\"", "$${user}"]);
+    }
+
+    fn failure_case(input: &str, category: &str) {
+        let ast = rnix::parse(input).as_result().unwrap();
+        let e = find_systemd_services(ast.root()).unwrap_err();
+        assert_eq!(crate::error::error_category(e.as_ref()), category);
+    }
+
+    #[test]
+    fn test_failure_categories() {
+        failure_case("{ config = { }; }", "non-lambda root");
+        failure_case("{ lib, ... }: { config = mkMerge cfg.configs; }", "mkMerge");
+        failure_case("{ lib, ... }: { config.${name}.services = { }; }", "dynamic key");
+        failure_case("{ lib, ... }: { config = lib.mapAttrs f cfg.instances; }", "function application");
+        failure_case("{ lib, ... }: { config = if cfg.enable then { } else { }; }", "if");
+        failure_case("{ lib, ... }: { inherit (cfg) config; }", "inherit");
+        failure_case("{ lib, ... }: { config.systemd.services = cfg.services; }", "reference");
+    }
+
+    #[test]
+    fn test_service_errors() {
+        let ast = rnix::parse("
+        { lib, ... }: {
+          config.systemd.services.a.serviceConfig = lib.mapAttrs f cfg.settings;
+          config.systemd.services.b.serviceConfig.User = \"b\";
+        }
+        ").as_result().unwrap();
+        let (services, _, errors) = declared_services(ast.root()).unwrap();
+
+        assert_eq!(services.iter().map(|(s, _)| s.as_str()).collect::<Vec<_>>(), ["b"]);
+        let errors: Vec<(&str, &str)> = errors.iter()
+            .map(|(s, e)| (s.as_str(), crate::error::error_category(e.as_ref())))
+            .collect();
+        assert_eq!(errors, [("a", "function application")]);
+    }
+}
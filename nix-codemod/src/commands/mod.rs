@@ -4,10 +4,18 @@ mod print_systemd_service_config;
 mod edit_systemd_service;
 mod find_all_tests;
 mod is_test_well_formed;
+mod discover_systemd_services;
+mod diff_discovery;
+mod serve;
+mod hook_modules;
 
 pub use list_systemd_services::*;
 pub use print_systemd_service_config::*;
 pub use edit_systemd_service::*;
 pub use find_all_tests::*;
 pub use is_test_well_formed::*;
+pub use discover_systemd_services::*;
+pub use diff_discovery::*;
+pub use serve::*;
+pub use hook_modules::*;
 
@@ -1,96 +1,303 @@
 
-use std::fs;
 use std::error::Error;
-
-use serde_json;
+use std::path::Path;
 
 use rnix::types::*;
-use rnix::SyntaxNode;
+use serde::{Serialize, Deserialize};
 
 use crate::walkers::*;
+use crate::catalog::*;
+use crate::imports::declaring_files;
+use crate::location::{Location, in_file};
+use crate::source::parse_file;
+
+pub fn service_config_entries(root: Root, service: &str) -> Result<DeclEntries, Box<dyn Error>> {
+    let decl = find_service_decl(root, service)?;
+    let cfg = decl.project("serviceConfig")?;
+
+    match cfg {
+        Some(cfg) => cfg.reduce_entries(),
+        None => Ok(vec!()),
+    }
+}
+
+pub fn blank_options<'a>(
+    catalog: &'a [HardeningOption],
+    entries: &DeclEntries
+) -> Result<Vec<&'a HardeningOption>, Box<dyn Error>> {
+    // an entry whose name we don't know may set any option
+    let dynamic = entries.iter().any(|(key, _)| key.first().is_none_or(|k| k.as_static().is_none()));
+
+    let mut blank = vec!();
+    for opt in catalog.iter() {
+        let decl = entries.iter().find(|(key, _)| key.first().is_some_and(|k| k.is(&opt.name)));
+        let value = match decl {
+            Some((key, _)) if key.len() > 1 => continue,
+            Some((_, kv)) => Some(parse_cfg_value(kv.value.clone())?),
+            None if dynamic => Some(CfgValue::NotReduced),
+            None => None,
+        };
+        if opt.is_blank(value.as_ref()) {
+            blank.push(opt);
+        }
+    }
+    Ok(blank)
+}
+
+/// An entry of a `serviceConfig`, with its value as we reduced it
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ConfigEntry {
+    pub name: String,
+    pub value: String,
+    pub location: Location,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ServiceConfig {
+    pub service: String,
+    /// Where the service is declared
+    pub location: Location,
+    pub entries: Vec<ConfigEntry>,
+    /// The options of the catalog the service doesn't harden
+    pub blank: Vec<String>,
+}
+
+/// Where `service` is declared in `root`, parsed from `file`, and the
+/// entries of its `serviceConfig` there
+fn file_service_config(
+    file: &Path,
+    root: Root,
+    service: &str
+) -> Result<(Location, DeclEntries, Vec<ConfigEntry>), Box<dyn Error>> {
+    let decl = find_service_decl(root.clone(), service)?;
+    let entries = service_config_entries(root, service)?;
+    let config_entries = entries.iter()
+        .map(|(key, kv)| Ok(ConfigEntry {
+            name: key_to_string(key),
+            location: Location::new(file, kv.position()),
+            value: parse_cfg_value(kv.value.clone())?.to_string(),
+        }))
+        .collect::<Result<Vec<ConfigEntry>, Box<dyn Error>>>()?;
 
-static bool_options: &'static [&'static str] = &[
-    "PrivateDevices",
-    "PrivateMounts",
-    "PrivateNetwork",
-    "PrivateTmp",
-    "PrivateUsers",
-    "ProtectControlGroups",
-    "ProtectKernelModules",
-    "ProtectKernelTunables",
-    "ProtectKernelLogs",
-    "ProtectClock",
-    "ProtectHostname",
-    "LockPersonality",
-    "MemoryDenyWriteExecute",
-    "NoNewPrivileges",
-    //"Delegate", -- inverted, so not here!
-    "RestrictRealtime",
-    "RestrictSUIDSGID",
-];
-
-fn find_service_decl(root: Root, service: &str) -> Result<DeclValue, Box<dyn Error>> {
-    let x = root.inner().and_then(Lambda::cast).ok_or("root isn't a function")?;
-    let x = go_right_value(x.body().ok_or("parse error")?)?;
-    decl_value(
-        &[  "config".to_string(),
-            "systemd".to_string(),
-            "services".to_string(),
-            service.to_string()],
-        x)?.ok_or(format!("config.systemd.services.{} is not declared", service).into())
+    Ok((Location::new(file, decl.position()), entries, config_entries))
+}
+
+/// The config of `service`, declared in `module` or its imports: the
+/// `serviceConfig` entries of all the files that declare the service
+pub fn service_config(module: &Path, service: &str, catalog: &[HardeningOption]) -> Result<ServiceConfig, Box<dyn Error>> {
+    let mut location = None;
+    let mut decl_entries = vec!();
+    let mut entries = vec!();
+
+    for file in declaring_files(module, service)? {
+        let (content, ast) = parse_file(&file)?;
+        let (file_location, file_decl_entries, file_entries) = file_service_config(&file, ast.root(), service)
+            .map_err(|e| in_file(&file, &content, e))?;
+        location.get_or_insert(file_location);
+        decl_entries.extend(file_decl_entries);
+        entries.extend(file_entries);
+    }
+
+    let blank = blank_options(catalog, &decl_entries)?.into_iter()
+        .map(|opt| opt.name.clone())
+        .collect();
+    let location = location.ok_or("no file declares the service")?;
+
+    Ok(ServiceConfig { service: service.to_string(), location, entries, blank })
 }
 
 pub fn print_systemd_service_config(
     module: &str,
     service: &str,
+    catalog: Option<&str>,
+    nixpkgs: Option<&str>,
     verbose: bool
 ) -> Result<(), Box<dyn Error>> {
-    let content = fs::read_to_string(&module)?;
-    let ast = rnix::parse(&content).as_result()?;
+    let catalog = load_target_catalog(catalog, nixpkgs)?;
+    let config = service_config(Path::new(module), service, &catalog)?;
 
-    let decl = find_service_decl(ast.root(), &service)?;
-    let cfg = decl.project("serviceConfig")?;
-
-    let entries = if let Some(entries) = cfg.map(DeclValue::entries).transpose()?.unwrap_or(Some(vec!())) {
-        entries
-    } else {
-        Err("serviceConfig is not an attribute set")?
-    };
+    if verbose {
+        println!("{} is declared at {}", service, config.location);
+        for ConfigEntry { name, value, location } in config.entries.iter() {
+            println!(" * {} = {} (line {})", name, value, location.line);
+        }
 
-    let configured: Vec<String> = entries.iter()
-        .filter_map(|(key, _)| if key.len() == 1 { Some(key.first().unwrap().clone()) } else { None })
-        .collect();
+        println!();
 
-    if verbose {
-        for (key, DeclKV { value, .. }) in entries.into_iter() {
-            print!(" * {} = ", key.join("."));
-
-            match parse_cfg_value(value)? {
-                CfgValue::Str(s) => {
-                    println!("{}", s);
-                },
-                CfgValue::Bool(b) => {
-                    println!("{}", b);
-                },
-                CfgValue::List(elems) => {
-                    println!("[ {} ]", elems.join(" "));
-                },
-                CfgValue::NotReduced => {
-                    println!("<not reduced>");
-                },
+        for opt in catalog.iter().filter(|opt| config.blank.contains(&opt.name)) {
+            let values: Vec<String> = opt.values.iter().map(|v| v.to_string()).collect();
+            println!(" - {} ({}) : {}, secure: {}", opt.name, opt.value_type, values.join(" | "), opt.secure());
+            if let Some(version) = opt.min_systemd_version {
+                println!("   since systemd {}", version);
+            }
+            if !opt.description.is_empty() {
+                println!("   {}", opt.description);
             }
         }
 
         println!();
     }
 
-    let blank_options: Vec<&str> = bool_options.iter()
-        .filter(|opt| !configured.contains(&opt.to_string()))
-        .map(|s| *s)
-        .collect();
-
-    println!("{}", serde_json::to_string(&blank_options)?);
+    println!("{}", serde_json::to_string(&config)?);
 
     Ok(())
 }
 
+#[cfg(test)]
+mod print_tests {
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+
+    fn test_case(input: &str, output: &[(&str, &str)]) {
+        let ast = rnix::parse(input).as_result().unwrap();
+        let entries: Vec<(String, String)> = service_config_entries(ast.root(), "codemod").unwrap()
+            .into_iter()
+            .map(|(key, kv)| (key_to_string(&key), parse_cfg_value(kv.value).unwrap().to_string()))
+            .collect();
+        let output: Vec<(String, String)> = output.iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+
+        assert_eq!(entries, output);
+    }
+
+    #[test]
+    fn test_cfg_values() {
+        test_case(r#"
+        { lib, pkgs, ... }:
+        let
+          user = "codemod";
+        in {
+          config.systemd.services.codemod.serviceConfig = {
+            UMask = "0077";
+            User = user;
+            LimitNOFILE = 65536;
+            Nice = -5;
+            RuntimeDirectoryMode = 0750;
+            ExecStart = "${pkgs.codemod}/bin/codemod --flag";
+            WorkingDirectory = ./.;
+            Restart = null;
+            PrivateTmp = lib.mkDefault true;
+            ProtectSystem = mkForce "strict";
+            ProtectHome = lib.mkOverride 900 "read-only";
+            SystemCallFilter = [ "@system-service" "~@privileged" ];
+            Environment = { A = "b"; };
+            ExecStartPre = pkgs.writeScript "pre" "";
+          };
+        }
+        "#, &[
+            ("UMask", r#""0077""#),
+            ("User", r#""codemod""#),
+            ("LimitNOFILE", "65536"),
+            ("Nice", "-5"),
+            ("RuntimeDirectoryMode", "750"),
+            ("ExecStart", r#""${pkgs.codemod}/bin/codemod --flag""#),
+            ("WorkingDirectory", "./."),
+            ("Restart", "null"),
+            ("PrivateTmp", "mkDefault true"),
+            ("ProtectSystem", r#"mkForce "strict""#),
+            ("ProtectHome", r#"mkOverride 900 "read-only""#),
+            ("SystemCallFilter", r#"[ "@system-service" "~@privileged" ]"#),
+            ("Environment", r#"{ A = "b"; }"#),
+            ("ExecStartPre", "<not reduced>"),
+        ]);
+    }
+
+    #[test]
+    fn test_cyclic_bindings() {
+        for bindings in ["a = a;", "a = b; b = a;", "a = { x = a; y = a; };"] {
+            let ast = rnix::parse(&format!("
+            {{ lib, ... }}:
+            let
+              {}
+            in {{
+              config.systemd.services.codemod.serviceConfig = {{
+                User = a;
+              }};
+            }}
+            ", bindings)).as_result().unwrap();
+            let (_, kv) = service_config_entries(ast.root(), "codemod").unwrap().remove(0);
+            let e = parse_cfg_value(kv.value).unwrap_err();
+            assert_eq!(crate::error::error_category(e.as_ref()), "too deep");
+        }
+    }
+
+    fn blank_test_case(input: &str, expected: &[&str], not_expected: &[&str]) {
+        let ast = rnix::parse(input).as_result().unwrap();
+        let entries = service_config_entries(ast.root(), "codemod").unwrap();
+
+        let catalog = builtin_catalog();
+        let blank: Vec<&str> = blank_options(&catalog, &entries).unwrap().into_iter()
+            .map(|opt| opt.name.as_str())
+            .collect();
+
+        for name in expected {
+            assert!(blank.contains(name), "{} should be blank", name);
+        }
+        for name in not_expected {
+            assert!(!blank.contains(name), "{} shouldn't be blank", name);
+        }
+    }
+
+    #[test]
+    fn test_blank_options() {
+        blank_test_case(r#"
+        { lib, pkgs, ... }: {
+          config.systemd.services.codemod.serviceConfig = {
+            PrivateTmp = true;
+            PrivateDevices = false;
+            ProtectSystem = "strict";
+            ProtectHome = lib.mkDefault "read-only";
+            SystemCallFilter = [ "@system-service" ];
+          };
+        }
+        "#,
+        &["PrivateDevices", "ProcSubset", "CapabilityBoundingSet"],
+        &["PrivateTmp", "ProtectSystem", "ProtectHome", "SystemCallFilter"]);
+    }
+
+    #[test]
+    fn test_set_and_entries() {
+        let input = r#"
+        { lib, pkgs, ... }: {
+          config.systemd.services.codemod.serviceConfig = { PrivateTmp = false; };
+          config.systemd.services.codemod.serviceConfig.User = "a";
+        }
+        "#;
+        test_case(input, &[
+            ("PrivateTmp", "false"),
+            ("User", r#""a""#),
+        ]);
+        blank_test_case(input, &["PrivateTmp"], &["User"]);
+    }
+
+    #[test]
+    fn test_unreduced_operand() {
+        let input = r#"
+        { lib, pkgs, ... }: {
+          config.systemd.services.codemod.serviceConfig = {
+            User = "codemod";
+          } // cfg.serviceOverrides;
+        }
+        "#;
+        test_case(input, &[
+            ("User", r#""codemod""#),
+            ("cfg.serviceOverrides", "<not reduced>"),
+        ]);
+        blank_test_case(input, &[], &["PrivateTmp", "ProtectHome"]);
+    }
+
+    #[test]
+    fn test_inverted_options() {
+        blank_test_case(r#"
+        { lib, pkgs, ... }: {
+          config.systemd.services.codemod.serviceConfig = {
+            Delegate = true;
+          };
+        }
+        "#,
+        &["Delegate"],
+        &["PermissionsStartOnly"]);
+    }
+}
@@ -0,0 +1,207 @@
+
+use std::error::Error;
+use std::path::{Path, PathBuf};
+use std::io::{self, BufRead, Write};
+
+use serde::{Serialize, Deserialize};
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+
+use crate::catalog::*;
+use crate::edit::{Edit, FileEdits};
+use crate::error::{error_category, catch_panic};
+use crate::location::{Location, FileError};
+use crate::source::keep_parsed_files;
+use super::{all_tests, hookable_options};
+
+#[derive(Deserialize)]
+struct Request {
+    #[serde(default)]
+    id: Value,
+    method: String,
+    #[serde(default)]
+    params: Value,
+}
+
+#[derive(Deserialize)]
+struct ListServices {
+    module: String,
+}
+
+#[derive(Deserialize)]
+struct ServiceConfigParams {
+    module: String,
+    service: String,
+    catalog: Option<String>,
+    nixpkgs: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct EditService {
+    module: String,
+    service: String,
+    options: Vec<(String, String)>,
+    catalog: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct InsertHooks {
+    module: String,
+    service: String,
+    option_names: Vec<String>,
+    catalog: Option<String>,
+    nixpkgs: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct FindAllTests {
+    all_tests: String,
+}
+
+/// The file an edit command would write, and its new content
+#[derive(Serialize)]
+struct Edited {
+    file: PathBuf,
+    content: String,
+    edits: Vec<Edit>,
+}
+
+impl Edited {
+    fn new(edits: FileEdits) -> Result<Edited, Box<dyn Error>> {
+        Ok(Edited { content: edits.apply()?, file: edits.file, edits: edits.edits })
+    }
+}
+
+#[derive(Serialize)]
+struct ResponseError {
+    message: String,
+    category: &'static str,
+    location: Option<Location>,
+}
+
+impl ResponseError {
+    fn new(e: Box<dyn Error>) -> ResponseError {
+        let category = error_category(e.as_ref());
+        match e.downcast::<FileError>() {
+            Ok(e) => ResponseError { message: e.message(), category, location: e.location.clone() },
+            Err(e) => ResponseError { message: e.to_string(), category, location: None },
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct Response {
+    id: Value,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    result: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<ResponseError>,
+}
+
+fn params<T: DeserializeOwned>(params: Value) -> Result<T, Box<dyn Error>> {
+    Ok(serde_json::from_value(params).map_err(|e| format!("invalid params: {}", e))?)
+}
+
+fn handle(method: &str, p: Value) -> Result<Value, Box<dyn Error>> {
+    let result = match method {
+        "list_services" => {
+            let ListServices { module } = params(p)?;
+            serde_json::to_value(crate::find_services(&module)?)?
+        },
+        "service_config" => {
+            let ServiceConfigParams { module, service, catalog, nixpkgs } = params(p)?;
+            let catalog = load_target_catalog(catalog.as_deref(), nixpkgs.as_deref())?;
+            serde_json::to_value(crate::service_config(&module, &service, &catalog)?)?
+        },
+        "edit_service" => {
+            let EditService { module, service, options, catalog } = params(p)?;
+            let catalog = load_catalog(catalog.as_deref())?;
+            serde_json::to_value(Edited::new(crate::service_edits(&module, &service, &options, &catalog)?)?)?
+        },
+        "insert_hooks" => {
+            let InsertHooks { module, service, option_names, catalog, nixpkgs } = params(p)?;
+            let hooked = hookable_options(&load_catalog(catalog.as_deref())?, &option_names, nixpkgs.as_deref())?;
+            serde_json::to_value(Edited::new(crate::hook_edits(&module, &service, &hooked)?)?)?
+        },
+        "find_all_tests" => {
+            let FindAllTests { all_tests } = params(p)?;
+            serde_json::to_value(self::all_tests(Path::new(&all_tests))?)?
+        },
+        _ => Err(format!("unknown method {}", method))?,
+    };
+    Ok(result)
+}
+
+fn respond(line: &str) -> Response {
+    let (id, result) = match serde_json::from_str::<Request>(line) {
+        Ok(Request { id, method, params }) => (id, catch_panic(|| handle(&method, params))),
+        Err(e) => (Value::Null, Err(format!("invalid request: {}", e).into())),
+    };
+    match result {
+        Ok(result) => Response { id, result: Some(result), error: None },
+        Err(e) => Response { id, result: None, error: Some(ResponseError::new(e)) },
+    }
+}
+
+/// Answers the requests on stdin, one JSON object per line, with one JSON
+/// object per line on stdout. The files stay parsed until they change.
+pub fn serve() -> Result<(), Box<dyn Error>> {
+    keep_parsed_files();
+    let mut stdout = io::stdout().lock();
+
+    for line in io::stdin().lock().lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue
+        }
+        writeln!(stdout, "{}", serde_json::to_string(&respond(&line))?)?;
+        stdout.flush()?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod serve_tests {
+    use pretty_assertions::assert_eq;
+
+    use crate::test_dir::TestDir;
+    use super::*;
+
+    #[test]
+    fn test_requests() {
+        let dir = TestDir::new("serve");
+        let module = dir.write("module.nix", "{ config, ... }: {\n  config.systemd.services.foo = {\n    wantedBy = [ ];\n  };\n}\n");
+        let module = module.to_str().unwrap();
+
+        let request = |method: &str, params: Value| {
+            let line = serde_json::json!({ "id": 1, "method": method, "params": params }).to_string();
+            serde_json::to_value(respond(&line)).unwrap()
+        };
+
+        assert_eq!(request("list_services", serde_json::json!({ "module": module })), serde_json::json!({
+            "id": 1,
+            "result": [{ "service": "foo", "location": { "file": module, "line": 2, "column": 3 } }],
+        }));
+
+        let response = request("insert_hooks", serde_json::json!({
+            "module": module, "service": "foo", "option_names": ["PrivateTmp"],
+        }));
+        assert_eq!(response["result"]["content"], "\
+{ systemdPassthru, config, ... }: {
+  config.systemd.services.foo = {
+    wantedBy = [ ];
+    serviceConfig = {
+      PrivateTmp = systemdPassthru.foo.PrivateTmp;
+    };
+  };
+}
+");
+
+        let response = request("service_config", serde_json::json!({ "module": module, "service": "bar" }));
+        assert!(response["error"]["message"].as_str().unwrap().contains("bar is not declared"));
+
+        let response = request("frobnicate", Value::Null);
+        assert_eq!(response["error"]["message"], "unknown method frobnicate");
+    }
+}
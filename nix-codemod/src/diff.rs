@@ -0,0 +1,199 @@
+
+use std::env;
+use std::error::Error;
+use std::path::{Component, Path, PathBuf};
+
+/// Lines of context around the changes
+const CONTEXT: usize = 3;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Op {
+    Equal,
+    Delete,
+    Insert,
+}
+
+/// The shortest edit script from `a` to `b`, with Myers' algorithm
+fn diff_lines(a: &[&str], b: &[&str]) -> Vec<Op> {
+    let (n, m) = (a.len() as isize, b.len() as isize);
+    let max = n + m;
+    let index = |k: isize| (k + max + 1) as usize;
+    let mut v = vec![0isize; 2 * max as usize + 3];
+    let mut trace: Vec<Vec<isize>> = vec!();
+
+    'search: for d in 0..=max {
+        trace.push(v.clone());
+        for k in (-d..=d).step_by(2) {
+            let mut x = if k == -d || (k != d && v[index(k - 1)] < v[index(k + 1)]) {
+                v[index(k + 1)]
+            } else {
+                v[index(k - 1)] + 1
+            };
+            let mut y = x - k;
+            while x < n && y < m && a[x as usize] == b[y as usize] {
+                x += 1;
+                y += 1;
+            }
+            v[index(k)] = x;
+            if x >= n && y >= m {
+                break 'search
+            }
+        }
+    }
+
+    let mut ops = vec!();
+    let (mut x, mut y) = (n, m);
+    for (d, v) in trace.iter().enumerate().rev() {
+        let d = d as isize;
+        let k = x - y;
+        let prev_k = if k == -d || (k != d && v[index(k - 1)] < v[index(k + 1)]) { k + 1 } else { k - 1 };
+        let prev_x = v[index(prev_k)];
+        let prev_y = prev_x - prev_k;
+        while x > prev_x && y > prev_y {
+            ops.push(Op::Equal);
+            x -= 1;
+            y -= 1;
+        }
+        if d > 0 {
+            ops.push(if x == prev_x { Op::Insert } else { Op::Delete });
+        }
+        x = prev_x;
+        y = prev_y;
+    }
+    ops.reverse();
+    ops
+}
+
+/// `path` made absolute from the current directory, without `.` and `..`
+fn absolute_path(path: &Path) -> Result<PathBuf, Box<dyn Error>> {
+    let mut absolute = PathBuf::new();
+    for c in env::current_dir()?.join(path).components() {
+        match c {
+            Component::CurDir => (),
+            Component::ParentDir => { absolute.pop(); },
+            c => absolute.push(c),
+        }
+    }
+    Ok(absolute)
+}
+
+/// `file` relative to `root`, as `git apply` and `patch -p1` expect it once
+/// prefixed with `a/` or `b/`; relative paths are from the current directory
+pub fn relative_path(file: &Path, root: &Path) -> Result<String, Box<dyn Error>> {
+    let (file, root) = (absolute_path(file)?, absolute_path(root)?);
+    let relative = file.strip_prefix(&root)
+        .map_err(|_| format!("{} isn't under {}, the root of the diff", file.display(), root.display()))?;
+    Ok(relative.components()
+        .map(|c| c.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/"))
+}
+
+fn push_line(out: &mut String, prefix: char, line: &str) {
+    out.push(prefix);
+    out.push_str(line);
+    if !line.ends_with('\n') {
+        out.push_str("\n\\ No newline at end of file\n");
+    }
+}
+
+/// The unified diff from `old` to `new`, the content of `path`; empty if
+/// they're the same
+pub fn unified_diff(path: &str, old: &str, new: &str) -> String {
+    let a: Vec<&str> = old.split_inclusive('\n').collect();
+    let b: Vec<&str> = new.split_inclusive('\n').collect();
+    let ops = diff_lines(&a, &b);
+
+    // the line of `a` and of `b` each op starts at
+    let mut positions = Vec::with_capacity(ops.len());
+    let (mut i, mut j) = (0, 0);
+    for op in ops.iter() {
+        positions.push((i, j));
+        match op {
+            Op::Equal => { i += 1; j += 1; },
+            Op::Delete => i += 1,
+            Op::Insert => j += 1,
+        }
+    }
+
+    // the ranges of ops in each hunk, the changes with their context
+    let mut hunks: Vec<(usize, usize)> = vec!();
+    for (n, op) in ops.iter().enumerate() {
+        if *op == Op::Equal {
+            continue
+        }
+        let (start, end) = (n.saturating_sub(CONTEXT), (n + 1 + CONTEXT).min(ops.len()));
+        match hunks.last_mut() {
+            Some(last) if start <= last.1 => last.1 = end,
+            _ => hunks.push((start, end)),
+        }
+    }
+
+    if hunks.is_empty() {
+        return String::new()
+    }
+
+    let mut out = format!("--- a/{}\n+++ b/{}\n", path, path);
+    for (start, end) in hunks {
+        let ops = &ops[start..end];
+        let old_count = ops.iter().filter(|op| **op != Op::Insert).count();
+        let new_count = ops.iter().filter(|op| **op != Op::Delete).count();
+        let (i, j) = positions[start];
+        // an empty range starts at the line before it
+        let old_start = if old_count == 0 { i } else { i + 1 };
+        let new_start = if new_count == 0 { j } else { j + 1 };
+        out.push_str(&format!("@@ -{},{} +{},{} @@\n", old_start, old_count, new_start, new_count));
+
+        for (op, (i, j)) in ops.iter().zip(positions[start..end].iter()) {
+            match op {
+                Op::Equal => push_line(&mut out, ' ', a[*i]),
+                Op::Delete => push_line(&mut out, '-', a[*i]),
+                Op::Insert => push_line(&mut out, '+', b[*j]),
+            }
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod diff_tests {
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+
+    #[test]
+    fn test_unified_diff() {
+        let old = "a\nb\nc\nd\ne\nf\ng\nh\ni\nj\n";
+        let new = "a\nB\nc\nd\ne\nf\ng\nh\ni\nj\nk";
+        assert_eq!(unified_diff("x.nix", old, new), "\
+--- a/x.nix
++++ b/x.nix
+@@ -1,5 +1,5 @@
+ a
+-b
++B
+ c
+ d
+ e
+@@ -8,3 +8,4 @@
+ h
+ i
+ j
++k
+\\ No newline at end of file
+");
+        assert_eq!(unified_diff("x.nix", old, old), "");
+        assert_eq!(unified_diff("x.nix", "", "a\n"), "--- a/x.nix\n+++ b/x.nix\n@@ -0,0 +1,1 @@\n+a\n");
+    }
+
+    #[test]
+    fn test_relative_path() {
+        let relative = |file: &str, root: &str| relative_path(Path::new(file), Path::new(root));
+        assert_eq!(relative("/nixpkgs/nixos/modules/a.nix", "/nixpkgs").unwrap(), "nixos/modules/a.nix");
+        assert_eq!(relative("/nixpkgs/nixos/../nixos/./modules/a.nix", "/nixpkgs/").unwrap(), "nixos/modules/a.nix");
+        assert_eq!(relative("./nixos/modules/a.nix", ".").unwrap(), "nixos/modules/a.nix");
+        assert_eq!(relative("../a.nix", "..").unwrap(), "a.nix");
+        assert!(relative("/tmp/a.nix", "/nixpkgs").is_err());
+        assert!(relative("../a.nix", ".").is_err());
+    }
+}
@@ -1,49 +1,95 @@
 
+use std::fs;
+use std::fmt;
+use std::iter;
 use std::error::Error;
+use std::path::PathBuf;
 
 //use rnix::types::*;
 use rnix::SyntaxNode;
 use rnix::SyntaxToken;
 use rnix::SyntaxKind;
 use rnix::NodeOrToken;
+use rnix::SyntaxElement;
+use rnix::TextRange;
+use serde::{Serialize, Deserialize};
 
+use crate::error::{CodemodError, parse_error};
+use crate::location::in_file;
+
+/// Replaces the bytes `start..end` of a file with `replace`
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct Edit {
     pub start: usize,
     pub end: usize,
     pub replace: String,
 }
 
-pub fn apply_edits(mut edits: Vec<Edit>, text: &mut String) {
+/// Edits of a file, along with its content before them
+#[derive(Clone, Debug)]
+pub struct FileEdits {
+    pub file: PathBuf,
+    pub content: String,
+    pub edits: Vec<Edit>,
+}
+
+impl FileEdits {
+    /// The content of the file once edited
+    pub fn apply(&self) -> Result<String, Box<dyn Error>> {
+        let mut text = self.content.clone();
+        apply_edits(self.edits.clone(), &mut text).map_err(|e| in_file(&self.file, &self.content, e))?;
+        Ok(text)
+    }
+
+    /// Replaces the file with its edited content, keeping the original one
+    /// in `<file>.orig` if `backup`. The content is written to a temporary
+    /// file first, so that the file is never half written.
+    pub fn write(&self, backup: bool) -> Result<(), Box<dyn Error>> {
+        let text = self.apply()?;
+        let with_extension = |extension: &str| {
+            let mut name = self.file.clone().into_os_string();
+            name.push(extension);
+            PathBuf::from(name)
+        };
+
+        if backup {
+            fs::write(with_extension(".orig"), &self.content)?;
+        }
+        let tmp = with_extension(".nix-codemod.tmp");
+        fs::write(&tmp, text)?;
+        fs::set_permissions(&tmp, fs::metadata(&self.file)?.permissions())?;
+        fs::rename(&tmp, &self.file)?;
+        Ok(())
+    }
+}
+
+pub fn apply_edits(mut edits: Vec<Edit>, text: &mut String) -> Result<(), Box<dyn Error>> {
     // essentially https://github.com/rust-lang/rust-analyzer/blob/master/crates/text-edit/src/lib.rs
     edits.sort_by_key(|e| (e.start, e.end));
-    if !Iterator::zip(edits.iter(), edits.iter().skip(1)).all(|(l, r)| l.start <= r.end) {
-        panic!("Overlapping edits, this is a bug!")
+    if let Some((l, r)) = Iterator::zip(edits.iter(), edits.iter().skip(1)).find(|(l, r)| l.end > r.start) {
+        Err(CodemodError::OverlappingEdits(TextRange::new((r.start as u32).into(), (l.end as u32).into())))?
     }
 
     for e in edits.into_iter().rev() {
         text.replace_range(e.start..e.end, &e.replace);
     }
+    Ok(())
 }
 
 /// Returns the end of the range to delete when deleting
-/// the token `n`; and wether this range ends at the end
+/// the element `n`; and wether this range ends at the end
 /// of a line.
-pub fn find_span_end(n: &SyntaxNode) -> (usize, bool) {
+pub fn find_span_end(n: &SyntaxElement) -> (usize, bool) {
     let mut end: usize = n.text_range().end().into();
-    let mut node: NodeOrToken<SyntaxNode, SyntaxToken> = NodeOrToken::Node(n.clone());
+    let mut node = n.clone();
 
     let is_line_end = loop {
         if let Some(n) = node.next_sibling_or_token() {
             node = n;
         }
 
-        let s = match node.kind() {
-            SyntaxKind::TOKEN_WHITESPACE => {
-                match &node {
-                    NodeOrToken::Token(token) => token.text(),
-                    _ => unreachable!()
-                }
-            },
+        let s = match &node {
+            NodeOrToken::Token(token) if token.kind() == SyntaxKind::TOKEN_WHITESPACE => token.text(),
             _ => break false
         };
 
@@ -56,22 +102,17 @@ pub fn find_span_end(n: &SyntaxNode) -> (usize, bool) {
     (end, is_line_end)
 }
 
-pub fn find_span_start(n: &SyntaxNode) -> usize {
+pub fn find_span_start(n: &SyntaxElement) -> usize {
     let mut start: usize = n.text_range().start().into();
-    let mut node: NodeOrToken<SyntaxNode, SyntaxToken> = NodeOrToken::Node(n.clone());
+    let mut node = n.clone();
 
     loop {
         if let Some(n) = node.prev_sibling_or_token() {
             node = n;
         }
 
-        let s = match node.kind() {
-            SyntaxKind::TOKEN_WHITESPACE => {
-                match &node {
-                    NodeOrToken::Token(token) => token.text(),
-                    _ => unreachable!()
-                }
-            },
+        let s = match &node {
+            NodeOrToken::Token(token) if token.kind() == SyntaxKind::TOKEN_WHITESPACE => token.text(),
             _ => break
         };
 
@@ -99,12 +140,17 @@ pub fn replace_node(n: &SyntaxNode, replace: String) -> Edit {
 }
 
 pub fn remove_node(n: &SyntaxNode) -> Edit {
-    let range = n.text_range();
+    let n = NodeOrToken::Node(n.clone());
+    remove_span(&n, &n)
+}
 
-    let (end, is_line_end) = find_span_end(n);
+/// Removes the elements from `first` to `last`, along with their line if
+/// nothing else is on it
+fn remove_span(first: &SyntaxElement, last: &SyntaxElement) -> Edit {
+    let (end, is_line_end) = find_span_end(last);
     let start = if is_line_end {
-        find_span_start(n)
-    } else { range.start().into() };
+        find_span_start(first)
+    } else { first.text_range().start().into() };
 
     Edit {
         start,
@@ -113,47 +159,232 @@ pub fn remove_node(n: &SyntaxNode) -> Edit {
     }
 }
 
-/// Expects an `AttrSet` node
-pub fn guess_indent(n: &SyntaxNode) -> Result<Option<usize>, Box<dyn Error>> {
-    let n = {
-        let mut n = n.first_child_or_token().ok_or("parse error")?;
-        loop {
-            if n.kind() == SyntaxKind::TOKEN_REC {
-                n = n.next_sibling_or_token().ok_or("parse error")?
-            } else if n.kind() == SyntaxKind::TOKEN_WHITESPACE {
-                n = n.next_sibling_or_token().ok_or("parse error")?
-            } else {
-                break
-            }
+/// The comments on the lines right above `n`, with no blank line in between
+pub fn leading_comments(n: &SyntaxNode) -> Vec<SyntaxToken> {
+    let mut comments = vec!();
+    let mut prev = n.prev_sibling_or_token();
+
+    loop {
+        let ws = match prev.and_then(NodeOrToken::into_token) {
+            Some(ws) if ws.kind() == SyntaxKind::TOKEN_WHITESPACE && ws.text().matches('\n').count() == 1 => ws,
+            _ => break,
+        };
+        let comment = match ws.prev_sibling_or_token().and_then(NodeOrToken::into_token) {
+            Some(comment) if comment.kind() == SyntaxKind::TOKEN_COMMENT => comment,
+            _ => break,
+        };
+        // a comment after something else belongs to its line
+        match comment.prev_sibling_or_token().and_then(NodeOrToken::into_token) {
+            Some(ws) if ws.kind() == SyntaxKind::TOKEN_WHITESPACE && ws.text().contains('\n') => (),
+            _ => break,
         }
-        n
-    };
+        prev = comment.prev_sibling_or_token();
+        comments.push(comment);
+    }
 
-    if n.kind() != SyntaxKind::TOKEN_CURLY_B_OPEN { Err("parse error")? }
-    
-    if let Some(n) = n.next_sibling_or_token() {
-        if n.kind() == SyntaxKind::TOKEN_WHITESPACE {
-            if let NodeOrToken::Token(token) = n {
-                if let Some(i) = token.text().rfind("\n") {
-                    //TODO: handle spaces & tabs ?
-                    let end: usize = token.text_range().end().into();
-                    let start: usize = token.text_range().start().into();
-                    return Ok(Some(end - (start + i + 1)))
-                }
+    comments.reverse();
+    comments
+}
+
+/// The `#` comment that ends the line `n` ends on
+pub fn trailing_comment(n: &SyntaxNode) -> Option<SyntaxToken> {
+    let mut next = n.next_sibling_or_token()?;
+    if let Some(ws) = next.as_token().filter(|t| t.kind() == SyntaxKind::TOKEN_WHITESPACE) {
+        if ws.text().contains('\n') {
+            return None
+        }
+        next = ws.next_sibling_or_token()?;
+    }
+    next.into_token().filter(|t| t.kind() == SyntaxKind::TOKEN_COMMENT && t.text().starts_with('#'))
+}
+
+/// Removes the entry `n` of an attribute set, with its comments
+pub fn remove_entry(n: &SyntaxNode) -> Edit {
+    let first = leading_comments(n).into_iter().next()
+        .map_or_else(|| NodeOrToken::Node(n.clone()), NodeOrToken::Token);
+    let last = trailing_comment(n)
+        .map_or_else(|| NodeOrToken::Node(n.clone()), NodeOrToken::Token);
+    remove_span(&first, &last)
+}
+
+/// The indentation of the line `n` starts, if nothing precedes it there
+pub fn line_indent(n: &SyntaxNode) -> Option<String> {
+    let ws = n.prev_sibling_or_token()?.into_token()?;
+    if ws.kind() != SyntaxKind::TOKEN_WHITESPACE {
+        return None
+    }
+    let text = ws.text();
+    text.rfind('\n').map(|i| text[i + 1..].to_string())
+}
+
+/// The lines of `text`, the ones after the first one without the
+/// indentation `indent`
+pub fn unindent(text: &str, indent: &str) -> Vec<String> {
+    text.split('\n')
+        .enumerate()
+        .map(|(i, line)| match line.strip_prefix(indent) {
+            Some(line) if i > 0 => line.to_string(),
+            _ => line.to_string(),
+        })
+        .collect()
+}
+
+/// Removes the identifiers `names` from the `Inherit` node `n`, or the
+/// whole statement if it doesn't inherit anything else.
+pub fn remove_inherited(n: &SyntaxNode, names: &[String]) -> Vec<Edit> {
+    let (removed, kept): (Vec<SyntaxNode>, Vec<SyntaxNode>) = n.children()
+        .filter(|n| n.kind() == SyntaxKind::NODE_IDENT)
+        .partition(|n| names.contains(&n.text().to_string()));
+
+    if removed.is_empty() {
+        vec!()
+    } else if kept.is_empty() {
+        vec!(remove_node(n))
+    } else {
+        removed.iter()
+            .map(|ident| {
+                let range = ident.text_range();
+                let start = match ident.prev_sibling_or_token() {
+                    Some(prev) if prev.kind() == SyntaxKind::TOKEN_WHITESPACE => prev.text_range().start(),
+                    _ => range.start(),
+                };
+                Edit { start: start.into(), end: range.end().into(), replace: "".to_string() }
+            })
+            .collect()
+    }
+}
+
+/// What a file indents each level with
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum IndentUnit {
+    Tab,
+    Spaces(usize),
+}
+
+impl IndentUnit {
+    /// Tabs if most indented lines of `text` start with one, otherwise 2 or
+    /// 4 spaces, whichever most lines are indented by relative to the one
+    /// above them
+    pub fn guess(text: &str) -> IndentUnit {
+        let (mut tabs, mut spaces) = (0, 0);
+        let (mut two, mut four) = (0, 0);
+        let mut previous = 0;
+
+        for line in text.lines().filter(|l| !l.trim().is_empty()) {
+            if line.starts_with('\t') {
+                tabs += 1;
+                continue
+            }
+            let width = line.len() - line.trim_start_matches(' ').len();
+            if width > 0 {
+                spaces += 1;
+            }
+            match width.checked_sub(previous) {
+                Some(2) => two += 1,
+                Some(4) => four += 1,
+                _ => (),
             }
+            previous = width;
+        }
+
+        if tabs > spaces {
+            IndentUnit::Tab
+        } else if four > two {
+            IndentUnit::Spaces(4)
+        } else {
+            IndentUnit::Spaces(2)
+        }
+    }
+}
+
+impl fmt::Display for IndentUnit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            IndentUnit::Tab => write!(f, "\t"),
+            IndentUnit::Spaces(n) => write!(f, "{:n$}", "", n=n),
         }
     }
+}
+
+/// The indentation of the entries of a set, and the unit of its file
+#[derive(Clone, Debug, PartialEq)]
+pub struct Indent {
+    pub base: String,
+    pub unit: IndentUnit,
+}
 
-    Ok(None)
+/// The text of the file `n` was parsed from
+fn file_text(n: &SyntaxNode) -> String {
+    n.ancestors().last().unwrap_or_else(|| n.clone()).text().to_string()
 }
 
-pub fn insert_at_set_end(n: &SyntaxNode, lines: &[String], indent: usize) -> Result<Edit, Box<dyn Error>> {
-    let n = n.last_child_or_token().ok_or("parse error")?;
-    if n.kind() != SyntaxKind::TOKEN_CURLY_B_CLOSE { Err("parse error")? }
-    
-    let mut spot: usize = n.text_range().start().into();
+/// The tabs and spaces the line of `text` that `offset` is on starts with
+fn line_indent_at(text: &str, offset: usize) -> &str {
+    let start = text[..offset].rfind('\n').map_or(0, |i| i + 1);
+    let line = &text[start..];
+    &line[..line.find(|c| c != ' ' && c != '\t').unwrap_or(line.len())]
+}
+
+/// The indentation of the line `n` starts on, wherever on the line it starts
+pub fn line_indent_of(n: &SyntaxNode) -> String {
+    line_indent_at(&file_text(n), n.text_range().start().into()).to_string()
+}
+
+/// The `{` and `}` of the `AttrSet` node `set`
+fn set_braces(set: &SyntaxNode) -> Result<(SyntaxElement, SyntaxElement), Box<dyn Error>> {
+    let open = set.children_with_tokens()
+        .find(|n| n.kind() != SyntaxKind::TOKEN_REC && n.kind() != SyntaxKind::TOKEN_WHITESPACE)
+        .filter(|n| n.kind() == SyntaxKind::TOKEN_CURLY_B_OPEN)
+        .ok_or_else(|| parse_error(set))?;
+    let close = set.last_child_or_token()
+        .filter(|n| n.kind() == SyntaxKind::TOKEN_CURLY_B_CLOSE)
+        .ok_or_else(|| parse_error(set))?;
+    Ok((open, close))
+}
+
+/// The indentation of the entries of the `AttrSet` node `set`: the one of
+/// the first entry on a line of its own, or one level more than the line the
+/// set starts on
+pub fn guess_indent(set: &SyntaxNode) -> Result<Indent, Box<dyn Error>> {
+    let (open, close) = set_braces(set)?;
+    let text = file_text(set);
+    let unit = IndentUnit::guess(&text);
+
+    let first = iter::successors(open.next_sibling_or_token(), |n| n.next_sibling_or_token())
+        .take_while(|n| n != &close)
+        .filter_map(NodeOrToken::into_token)
+        .filter(|t| t.kind() == SyntaxKind::TOKEN_WHITESPACE && t.next_sibling_or_token().as_ref() != Some(&close))
+        .find_map(|t| t.text().rfind('\n').map(|i| t.text()[i + 1..].to_string()));
+
+    let base = match first {
+        Some(base) => base,
+        None => format!("{}{}", line_indent_at(&text, set.text_range().start().into()), unit),
+    };
+    Ok(Indent { base, unit })
+}
+
+/// Inserts `lines` at the end of the `AttrSet` node `set`, each on its own
+/// line indented with `indent`. A set on a single line gets each of its
+/// entries on its own line too, except the ones the edits `others` remove.
+pub fn insert_at_set_end(
+    set: &SyntaxNode,
+    lines: &[String],
+    indent: &Indent,
+    others: &[Edit]
+) -> Result<Vec<Edit>, Box<dyn Error>> {
+    let (open, close) = set_braces(set)?;
+
+    let replace = lines.iter()
+        .map(|s| if s.is_empty() { "\n".to_string() } else { format!("\n{}{}", indent.base, s) })
+        .collect::<String>();
 
-    if let Some(n) = n.prev_sibling_or_token() {
+    if !set.text().contains_char('\n') {
+        return Ok(expand_set(set, open, replace, indent, others))
+    }
+
+    let mut spot: usize = close.text_range().start().into();
+
+    if let Some(n) = close.prev_sibling_or_token() {
         if n.kind() == SyntaxKind::TOKEN_WHITESPACE {
             if let NodeOrToken::Token(token) = n {
                 spot = token.text_range().start().into();
@@ -162,16 +393,61 @@ pub fn insert_at_set_end(n: &SyntaxNode, lines: &[String], indent: usize) -> Res
         }
     }
 
-    let replace = lines.iter()
-        .map(|s| format!("\n{:indent$}{}", "", s, indent=indent))
-        .collect::<String>();
+    Ok(vec![Edit { start: spot, end: spot, replace }])
+}
+
+/// Puts each element of the single line `AttrSet` node `set` on its own
+/// line, followed by `inserted` and the closing brace. The whitespace
+/// between them is replaced, except around the elements the edits `others`
+/// remove, which already take the whitespace after them.
+fn expand_set(set: &SyntaxNode, open: SyntaxElement, inserted: String, indent: &Indent, others: &[Edit]) -> Vec<Edit> {
+    let text = file_text(set);
+    let closing = line_indent_at(&text, set.text_range().start().into());
+    let removed = |range: TextRange| {
+        let (start, end): (usize, usize) = (range.start().into(), range.end().into());
+        others.iter().any(|e| e.replace.is_empty() && e.start <= start && end <= e.end)
+    };
+
+    let mut edits = vec!();
+    let mut whitespace: Option<TextRange> = None;
+    for n in iter::successors(open.next_sibling_or_token(), |n| n.next_sibling_or_token()) {
+        // the next element goes where a removed one was
+        if !n.text_range().is_empty() && removed(n.text_range()) {
+            continue
+        }
+        if n.kind() == SyntaxKind::TOKEN_WHITESPACE {
+            whitespace = Some(n.text_range());
+            continue
+        }
+
+        let range = whitespace.take().unwrap_or_else(|| TextRange::empty(n.text_range().start()));
+        let replace = if n.kind() == SyntaxKind::TOKEN_CURLY_B_CLOSE {
+            format!("{}\n{}", inserted, closing)
+        } else {
+            format!("\n{}", indent.base)
+        };
+        edits.push(Edit { start: range.start().into(), end: range.end().into(), replace });
+    }
+    edits
+}
+
+/// Appends `// { ... }` with `lines` to the expression `n`, indented one
+/// level more than the line `n` ends on
+pub fn append_update(n: &SyntaxNode, lines: &[String]) -> Edit {
+    let text = file_text(n);
+    let end = n.text_range().end().into();
+    let base = line_indent_at(&text, end);
+    let unit = IndentUnit::guess(&text);
 
-    Ok(Edit { start: spot, end: spot, replace })
+    let replace = format!(" // {{{}\n{}}}",
+        lines.iter().map(|l| format!("\n{}{}{}", base, unit, l)).collect::<String>(),
+        base);
+    Edit { start: end, end, replace }
 }
 
-pub fn insert_at_pattern_start(n: &SyntaxNode, text: String) -> Result<Edit, Box<dyn Error>> {
-    let n = n.first_child_or_token().ok_or("parse error")?;
-    if n.kind() != SyntaxKind::TOKEN_CURLY_B_OPEN { Err("parse error")? }
+pub fn insert_at_pattern_start(pattern: &SyntaxNode, text: String) -> Result<Edit, Box<dyn Error>> {
+    let n = pattern.first_child_or_token().ok_or_else(|| parse_error(pattern))?;
+    if n.kind() != SyntaxKind::TOKEN_CURLY_B_OPEN { Err(parse_error(pattern))? }
 
     Ok(Edit {
         start: n.text_range().end().into(),
@@ -180,3 +456,19 @@ pub fn insert_at_pattern_start(n: &SyntaxNode, text: String) -> Result<Edit, Box
     })
 }
 
+
+#[cfg(test)]
+mod edit_tests {
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+
+    #[test]
+    fn test_indent_unit() {
+        assert_eq!(IndentUnit::guess("{\n\ta = {\n\t\tb = 1;\n\t};\n}"), IndentUnit::Tab);
+        assert_eq!(IndentUnit::guess("{\n    a = {\n        b = 1;\n    };\n}"), IndentUnit::Spaces(4));
+        assert_eq!(IndentUnit::guess("{\n  a = {\n    b = 1;\n  };\n}"), IndentUnit::Spaces(2));
+        assert_eq!(IndentUnit::guess("{ }"), IndentUnit::Spaces(2));
+        assert_eq!(IndentUnit::Spaces(4).to_string(), "    ");
+    }
+}
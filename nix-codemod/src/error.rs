@@ -0,0 +1,157 @@
+
+use std::fmt;
+use std::error::Error;
+
+use rnix::{SyntaxKind, SyntaxNode, TextRange};
+
+use crate::location::FileError;
+
+/// A construct the walkers can't reduce, and where it is
+#[derive(Clone, Debug, PartialEq)]
+pub enum CodemodError {
+    /// A node lacks a child it should have
+    Parse(TextRange),
+    /// The module isn't a function, like `{ config, ... }: { }`
+    NonLambdaRoot(TextRange),
+    /// `mkMerge` applied to something that isn't a list
+    MkMerge(TextRange),
+    /// An attribute name we can't compute, where it could be the one we look for
+    DynamicKey(TextRange),
+    /// A `//` we can't look into
+    Update(TextRange),
+    /// An inherited value that isn't bound in the file
+    Inherit(TextRange),
+    /// A variable, or an attribute of one, that isn't bound in the file
+    Reference(TextRange),
+    /// A function application, like `lib.mapAttrs f cfg.instances`
+    Apply(TextRange),
+    /// An `if ... then ... else ...`
+    IfElse(TextRange),
+    /// An attribute declared several times, in ways we can't merge
+    DefinedMultipleTimes(TextRange),
+    /// Bindings that refer to each other too deeply
+    TooDeep(TextRange),
+    /// Anything else where an attribute set is expected
+    NotAnAttrSet(TextRange),
+    /// Two edits of the same text; this is a bug
+    OverlappingEdits(TextRange),
+    /// The walkers panicked; this is a bug too
+    Panic(String),
+}
+
+impl CodemodError {
+    pub fn category(&self) -> &'static str {
+        match self {
+            CodemodError::Parse(_) => "parse",
+            CodemodError::NonLambdaRoot(_) => "non-lambda root",
+            CodemodError::MkMerge(_) => "mkMerge",
+            CodemodError::DynamicKey(_) => "dynamic key",
+            CodemodError::Update(_) => "//",
+            CodemodError::Inherit(_) => "inherit",
+            CodemodError::Reference(_) => "reference",
+            CodemodError::Apply(_) => "function application",
+            CodemodError::IfElse(_) => "if",
+            CodemodError::DefinedMultipleTimes(_) => "defined multiple times",
+            CodemodError::TooDeep(_) => "too deep",
+            CodemodError::NotAnAttrSet(_) => "not an attribute set",
+            CodemodError::OverlappingEdits(_) => "overlapping edits",
+            CodemodError::Panic(_) => "panic",
+        }
+    }
+
+    pub fn range(&self) -> Option<TextRange> {
+        Some(match self {
+            CodemodError::Parse(r)
+            | CodemodError::NonLambdaRoot(r)
+            | CodemodError::MkMerge(r)
+            | CodemodError::DynamicKey(r)
+            | CodemodError::Update(r)
+            | CodemodError::Inherit(r)
+            | CodemodError::Reference(r)
+            | CodemodError::Apply(r)
+            | CodemodError::IfElse(r)
+            | CodemodError::DefinedMultipleTimes(r)
+            | CodemodError::TooDeep(r)
+            | CodemodError::NotAnAttrSet(r)
+            | CodemodError::OverlappingEdits(r) => *r,
+            CodemodError::Panic(_) => return None,
+        })
+    }
+
+    /// What went wrong, without where
+    pub fn what(&self) -> String {
+        match self {
+            CodemodError::Parse(_) => "parse error",
+            CodemodError::NonLambdaRoot(_) => "root isn't a function",
+            CodemodError::MkMerge(_) => "couldn't reduce mkMerge",
+            CodemodError::DynamicKey(_) => "dynamic attribute name",
+            CodemodError::Update(_) => "couldn't reduce //",
+            CodemodError::Inherit(_) => "couldn't reduce inherited value",
+            CodemodError::Reference(_) => "couldn't reduce reference",
+            CodemodError::Apply(_) => "couldn't reduce function application",
+            CodemodError::IfElse(_) => "couldn't reduce if",
+            CodemodError::DefinedMultipleTimes(_) => "defined multiple times",
+            CodemodError::TooDeep(_) => "too many levels of bindings",
+            CodemodError::NotAnAttrSet(_) => "couldn't reduce",
+            CodemodError::OverlappingEdits(_) => "overlapping edits, this is a bug!",
+            CodemodError::Panic(message) => return format!("panicked: {}", message),
+        }.to_string()
+    }
+}
+
+impl fmt::Display for CodemodError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.range() {
+            Some(r) => write!(f, "{} at {}..{}", self.what(), usize::from(r.start()), usize::from(r.end())),
+            None => write!(f, "{}", self.what()),
+        }
+    }
+}
+
+impl Error for CodemodError {}
+
+pub fn parse_error(n: &SyntaxNode) -> CodemodError {
+    CodemodError::Parse(n.text_range())
+}
+
+/// Why `n`, which we expected to reduce to an attribute set, doesn't
+pub fn unsupported(n: &SyntaxNode) -> CodemodError {
+    let r = n.text_range();
+    match n.kind() {
+        SyntaxKind::NODE_APPLY => CodemodError::Apply(r),
+        SyntaxKind::NODE_IF_ELSE => CodemodError::IfElse(r),
+        SyntaxKind::NODE_BIN_OP => CodemodError::Update(r),
+        SyntaxKind::NODE_IDENT if n.parent().is_some_and(|p| p.kind() == SyntaxKind::NODE_INHERIT) =>
+            CodemodError::Inherit(r),
+        SyntaxKind::NODE_IDENT | SyntaxKind::NODE_SELECT => CodemodError::Reference(r),
+        _ => CodemodError::NotAnAttrSet(r),
+    }
+}
+
+/// The category of an error, for the ones that aren't a `CodemodError`
+/// (I/O, nix syntax, ...) too
+pub fn error_category(e: &(dyn Error + 'static)) -> &'static str {
+    if let Some(e) = e.downcast_ref::<FileError>() {
+        return error_category(e.error.as_ref())
+    }
+    match e.downcast_ref::<CodemodError>() {
+        Some(e) => e.category(),
+        None if e.downcast_ref::<std::io::Error>().is_some() => "io",
+        None if e.downcast_ref::<rnix::parser::ParseError>().is_some() => "syntax",
+        None => "other",
+    }
+}
+
+/// `f()`, with a panic turned into a `CodemodError::Panic`, so that batch
+/// commands can record it and carry on with the next module
+pub fn catch_panic<T>(f: impl FnOnce() -> Result<T, Box<dyn Error>>) -> Result<T, Box<dyn Error>> {
+    match std::panic::catch_unwind(std::panic::AssertUnwindSafe(f)) {
+        Ok(result) => result,
+        Err(payload) => {
+            let message = payload.downcast_ref::<&str>().map(|s| s.to_string())
+                .or_else(|| payload.downcast_ref::<String>().cloned())
+                .unwrap_or_else(|| "unknown".to_string());
+            Err(CodemodError::Panic(message))?
+        },
+    }
+}
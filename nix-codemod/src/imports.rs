@@ -0,0 +1,127 @@
+
+use std::path::{Path, PathBuf};
+use std::error::Error;
+
+use rnix::types::*;
+
+use crate::walkers::*;
+use crate::module_list::resolve;
+use crate::source::parse_file;
+use crate::location::in_file;
+
+/// The relative paths in a module's `imports = [ ... ]`
+fn relative_imports(root: Root) -> Result<Vec<String>, Box<dyn Error>> {
+    let x = root_body(&root)?;
+
+    match decl_value(&["imports".to_string()], x)? {
+        Some(DeclValue::Node(kv)) => {
+            let list = List::cast(resolve_value(kv.value)?).ok_or("imports isn't a list")?;
+            Ok(list.items()
+                .filter_map(|item| resolve_value(item).ok())
+                .filter_map(|item| expect_relative_path(item).ok())
+                .collect())
+        },
+        _ => Ok(vec!()),
+    }
+}
+
+/// `module`, then the files it imports with relative paths, recursively
+pub fn module_files(module: &Path) -> Result<Vec<PathBuf>, Box<dyn Error>> {
+    let mut files: Vec<PathBuf> = vec!();
+    let mut todo = vec![module.to_path_buf()];
+
+    while let Some(file) = todo.pop() {
+        if files.contains(&file) {
+            continue;
+        }
+        let (_, ast) = parse_file(&file)?;
+        let dir = file.parent().ok_or("module has no parent directory")?.to_path_buf();
+        // imports that don't parse as modules are still followed, but not looked into
+        let imports = relative_imports(ast.root()).unwrap_or_default();
+        files.push(file);
+        todo.extend(imports.iter().rev().map(|p| resolve(&dir, p)));
+    }
+
+    Ok(files)
+}
+
+/// The files that declare `config.systemd.services.<service>`, among `module`
+/// and its imports. A file we can't tell about is an error, located in it.
+pub fn declaring_files(module: &Path, service: &str) -> Result<Vec<PathBuf>, Box<dyn Error>> {
+    let mut files = vec!();
+    for file in module_files(module)? {
+        let (content, ast) = parse_file(&file)?;
+        match service_decl(ast.root(), service) {
+            Ok(Some(_)) => files.push(file),
+            Ok(None) => (),
+            Err(e) => Err(in_file(&file, &content, e))?,
+        }
+    }
+
+    if files.is_empty() {
+        Err(format!("config.systemd.services.{} is not declared", service).into())
+    } else {
+        Ok(files)
+    }
+}
+
+/// The file to edit the `serviceConfig` of `service` in: the first one that
+/// declares it, or else the first one that declares the service
+pub fn find_declaring_file(module: &Path, service: &str) -> Result<PathBuf, Box<dyn Error>> {
+    let files = declaring_files(module, service)?;
+    for file in files.iter() {
+        let (_, ast) = parse_file(file)?;
+        let cfg = find_service_decl(ast.root(), service)?.project("serviceConfig");
+        // a `serviceConfig` we can't reduce is still declared there
+        if !matches!(cfg, Ok(None)) {
+            return Ok(file.clone())
+        }
+    }
+    Ok(files[0].clone())
+}
+
+#[cfg(test)]
+mod imports_tests {
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+    use crate::test_dir::TestDir;
+
+    #[test]
+    fn test_relative_imports() {
+        let ast = rnix::parse(r#"
+        { config, lib, ... }: {
+          imports = [
+            ./options.nix
+            ./service
+            (./legacy.nix)
+            <nixpkgs/nixos/modules/misc/ids.nix>
+            (lib.mkRenamedOptionModule [ "a" ] [ "b" ])
+          ];
+          config = { };
+        }
+        "#).as_result().unwrap();
+
+        assert_eq!(relative_imports(ast.root()).unwrap(), vec!["./options.nix", "./service", "./legacy.nix"]);
+    }
+
+    #[test]
+    fn test_declaring_files_error() {
+        let dir = TestDir::new("declaring-files");
+        let module = dir.write("module.nix", "
+        { config, lib, ... }: {
+          imports = [ ./service.nix ];
+        }
+        ");
+        dir.write("service.nix", "
+        { config, lib, ... }: {
+          config.systemd.services.foo = { };
+          config.systemd.services.foo.serviceConfig = { };
+        }
+        ");
+
+        let e = declaring_files(&module, "foo").unwrap_err();
+        assert_eq!(crate::error::error_category(e.as_ref()), "defined multiple times");
+        assert!(e.to_string().contains("service.nix"), "{}", e);
+    }
+}
@@ -0,0 +1,170 @@
+//! Finds the systemd services NixOS modules declare, reads their
+//! `serviceConfig`, and computes the edits that harden them.
+//!
+//! The `nix-codemod` command line is a thin layer over these functions.
+
+mod error;
+mod walkers;
+mod catalog;
+mod nixpkgs;
+mod module_list;
+mod imports;
+mod cache;
+mod location;
+mod edit;
+mod source;
+mod diff;
+#[cfg(test)]
+mod test_dir;
+mod commands;
+
+use std::error::Error;
+use std::path::Path;
+
+pub use error::CodemodError;
+pub use location::{Location, Position, FileError};
+pub use edit::{Edit, FileEdits};
+pub use catalog::{HardeningOption, OptionValue, ValueType, builtin_catalog, load_catalog, load_target_catalog, find_option};
+pub use nixpkgs::systemd_version;
+pub use commands::{ServiceDecl, ServiceConfig, ConfigEntry};
+
+/// The commands of the `nix-codemod` binary, which print their results; they
+/// aren't part of the API
+#[doc(hidden)]
+pub mod cli {
+    pub use crate::commands::{
+        EditOutput,
+        list_systemd_services,
+        print_systemd_service_config,
+        edit_systemd_service,
+        insert_systemd_hooks,
+        hook_modules,
+        discover_systemd_services,
+        diff_discovery,
+        find_all_tests,
+        is_test_well_formed,
+        serve,
+    };
+}
+
+/// The services `module` declares, in the module itself or in the files it
+/// imports with relative paths
+pub fn find_services(module: &str) -> Result<Vec<ServiceDecl>, Box<dyn Error>> {
+    Ok(commands::module_services(Path::new(module))?.0)
+}
+
+/// The `serviceConfig` entries of `service`, and the options of `catalog`
+/// it doesn't harden
+pub fn service_config(module: &str, service: &str, catalog: &[HardeningOption]) -> Result<ServiceConfig, Box<dyn Error>> {
+    commands::service_config(Path::new(module), service, catalog)
+}
+
+/// The edits that set `options`, pairs of an option and a nix expression,
+/// in the `serviceConfig` of `service`; values of catalog options are checked
+pub fn service_edits(
+    module: &str,
+    service: &str,
+    options: &[(String, String)],
+    catalog: &[HardeningOption]
+) -> Result<FileEdits, Box<dyn Error>> {
+    commands::service_edits(Path::new(module), service, options, catalog, false)
+}
+
+/// The edits that make `option_names` settable through `systemdPassthru`,
+/// in the file that declares `service`
+pub fn hook_edits(module: &str, service: &str, option_names: &[String]) -> Result<FileEdits, Box<dyn Error>> {
+    commands::hook_edits(Path::new(module), service, option_names)
+}
+
+#[cfg(test)]
+mod lib_tests {
+    use std::fs;
+
+    use pretty_assertions::assert_eq;
+
+    use crate::test_dir::TestDir;
+    use super::*;
+
+    #[test]
+    fn test_api() {
+        let dir = TestDir::new("lib");
+        let module = dir.write("module.nix", "\
+{ config, lib, ... }: {
+  config.systemd.services.foo.serviceConfig = {
+    PrivateTmp = true;
+  };
+}
+");
+        let module = module.to_str().unwrap();
+        let location = |line, column| Location { file: module.to_string(), line, column };
+
+        assert_eq!(find_services(module).unwrap(), vec![
+            ServiceDecl { service: "foo".to_string(), location: location(2, 3) },
+        ]);
+
+        let catalog = builtin_catalog();
+        let config = service_config(module, "foo", &catalog).unwrap();
+        assert_eq!(config.entries, vec![ConfigEntry {
+            name: "PrivateTmp".to_string(),
+            value: "true".to_string(),
+            location: location(3, 5),
+        }]);
+        assert!(!config.blank.contains(&"PrivateTmp".to_string()));
+        assert!(config.blank.contains(&"ProtectHome".to_string()));
+
+        let edits = hook_edits(module, "foo", &["ProtectHome".to_string()]).unwrap();
+        assert_eq!(edits.apply().unwrap(), "\
+{ systemdPassthru, config, lib, ... }: {
+  config.systemd.services.foo.serviceConfig = {
+    PrivateTmp = true;
+    ProtectHome = systemdPassthru.foo.ProtectHome;
+  };
+}
+");
+
+        let original = edits.content.clone();
+        edits.write(true).unwrap();
+        assert_eq!(fs::read_to_string(module).unwrap(), edits.apply().unwrap());
+        assert_eq!(fs::read_to_string(format!("{}.orig", module)).unwrap(), original);
+        let edits = hook_edits(module, "foo", &["ProtectHome".to_string()]).unwrap();
+        assert_eq!(edits.apply().unwrap(), edits.content);
+    }
+
+    #[test]
+    fn test_imported_service_config() {
+        let dir = TestDir::new("lib-imports");
+        let module = dir.write("default.nix", "\
+{ config, lib, ... }: {
+  imports = [ ./service.nix ];
+  config.systemd.services.foo.wantedBy = [ ];
+}
+");
+        let service = dir.write("service.nix", "\
+{ config, lib, ... }: {
+  config.systemd.services.foo.serviceConfig.PrivateTmp = true;
+}
+");
+        let module = module.to_str().unwrap();
+
+        let catalog = builtin_catalog();
+        let config = service_config(module, "foo", &catalog).unwrap();
+        assert_eq!(config.location, Location { file: module.to_string(), line: 3, column: 3 });
+        assert_eq!(config.entries, vec![ConfigEntry {
+            name: "PrivateTmp".to_string(),
+            value: "true".to_string(),
+            location: Location { file: service.display().to_string(), line: 2, column: 3 },
+        }]);
+        assert!(!config.blank.contains(&"PrivateTmp".to_string()));
+
+        let edits = hook_edits(module, "foo", &["PrivateTmp".to_string(), "ProtectHome".to_string()]).unwrap();
+        assert_eq!(edits.file, service);
+        assert_eq!(edits.apply().unwrap(), "\
+{ systemdPassthru, config, lib, ... }: {
+  config.systemd.services.foo.serviceConfig = {
+    PrivateTmp = systemdPassthru.foo.PrivateTmp;
+    ProtectHome = systemdPassthru.foo.ProtectHome;
+  };
+}
+");
+    }
+}
@@ -0,0 +1,158 @@
+
+use std::fmt;
+use std::error::Error;
+use std::path::Path;
+
+use rnix::{SyntaxNode, TextRange, TextSize};
+use rnix::parser::ParseError;
+use serde::{Serialize, Deserialize};
+
+use crate::error::CodemodError;
+
+/// A line and a column, both starting at 1
+pub type Position = (usize, usize);
+
+/// A position in a nix file
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Location {
+    pub file: String,
+    pub line: usize,
+    pub column: usize,
+}
+
+impl Location {
+    pub fn new(file: &Path, (line, column): Position) -> Location {
+        Location { file: file.display().to_string(), line, column }
+    }
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}:{}", self.file, self.line, self.column)
+    }
+}
+
+/// The line and column of `offset` in `text`; columns count characters
+pub fn line_column(text: &str, offset: usize) -> Position {
+    let before = &text[..offset];
+    let line_start = before.rfind('\n').map_or(0, |i| i + 1);
+    (before.matches('\n').count() + 1, before[line_start..].chars().count() + 1)
+}
+
+/// The line and column where `n` starts, in the file it was parsed from
+pub fn position(n: &SyntaxNode) -> Position {
+    let root = n.ancestors().last().unwrap_or_else(|| n.clone());
+    line_column(&root.text().to_string(), n.text_range().start().into())
+}
+
+/// The line `range` starts on, with carets under `range`
+fn snippet(text: &str, range: TextRange) -> String {
+    let start: usize = range.start().into();
+    let end: usize = range.end().into();
+    let line_start = text[..start].rfind('\n').map_or(0, |i| i + 1);
+    let line_end = text[start..].find('\n').map_or(text.len(), |i| start + i);
+    let (line, _) = line_column(text, start);
+
+    // keep the tabs, so that the carets line up
+    let margin: String = text[line_start..start].chars()
+        .map(|c| if c == '\t' { '\t' } else { ' ' })
+        .collect();
+    let width = text[start..end.clamp(start, line_end)].chars().count().max(1);
+    let gutter = " ".repeat(line.to_string().len());
+
+    format!("{} |\n{} | {}\n{} | {}{}",
+        gutter, line, &text[line_start..line_end], gutter, margin, "^".repeat(width))
+}
+
+/// The range an error points to, for the ones that carry one
+fn error_range(e: &(dyn Error + 'static), text: &str) -> Option<TextRange> {
+    if let Some(e) = e.downcast_ref::<CodemodError>() {
+        return e.range()
+    }
+    match e.downcast_ref::<ParseError>()? {
+        ParseError::Unexpected(r)
+        | ParseError::UnexpectedExtra(r)
+        | ParseError::UnexpectedWanted(_, r, _)
+        | ParseError::UnexpectedDoubleBind(r)
+        | ParseError::DuplicatedArgs(r, _) => Some(*r),
+        ParseError::UnexpectedEOF
+        | ParseError::UnexpectedEOFWanted(_) => Some(TextRange::empty(TextSize::of(text))),
+        _ => None,
+    }
+}
+
+/// An error in a file, rendered like a compiler diagnostic
+#[derive(Debug)]
+pub struct FileError {
+    pub file: String,
+    pub location: Option<Location>,
+    snippet: Option<String>,
+    pub error: Box<dyn Error>,
+}
+
+impl FileError {
+    /// The error, without the location
+    pub fn message(&self) -> String {
+        match self.error.downcast_ref::<CodemodError>() {
+            Some(e) => e.what(),
+            None => self.error.to_string(),
+        }
+    }
+}
+
+impl fmt::Display for FileError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (&self.location, &self.snippet) {
+            (Some(location), Some(snippet)) => write!(f, "{}: {}\n{}", location, self.message(), snippet),
+            _ => write!(f, "{}: {}", self.file, self.message()),
+        }
+    }
+}
+
+impl Error for FileError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        Some(self.error.as_ref())
+    }
+}
+
+/// Locates `e`, which happened while reading `file`, whose content is `text`
+pub fn in_file(file: &Path, text: &str, e: Box<dyn Error>) -> Box<dyn Error> {
+    if e.is::<FileError>() {
+        return e
+    }
+    let range = error_range(e.as_ref(), text);
+    let location = range.map(|r| Location::new(file, line_column(text, r.start().into())));
+    let snippet = range.map(|r| snippet(text, r));
+    Box::new(FileError { file: file.display().to_string(), location, snippet, error: e })
+}
+
+#[cfg(test)]
+mod location_tests {
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+
+    #[test]
+    fn test_line_column() {
+        let text = "{\n  a = 1;\n\tb = \"é\"; c = 2;\n}";
+        assert_eq!(line_column(text, 0), (1, 1));
+        assert_eq!(line_column(text, text.find('a').unwrap()), (2, 3));
+        assert_eq!(line_column(text, text.find('c').unwrap()), (3, 11));
+    }
+
+    #[test]
+    fn test_diagnostic() {
+        let text = "{ lib, ... }: {\n\tconfig = lib.mapAttrs f cfg.instances;\n}\n";
+        let start = text.find("lib.mapAttrs").unwrap();
+        let end = text.find(';').unwrap();
+        let e = CodemodError::Apply(TextRange::new((start as u32).into(), (end as u32).into()));
+        let e = in_file(Path::new("module.nix"), text, Box::new(e));
+
+        assert_eq!(e.to_string(), "\
+module.nix:2:11: couldn't reduce function application
+  |
+2 | \tconfig = lib.mapAttrs f cfg.instances;
+  | \t         ^^^^^^^^^^^^^^^^^^^^^^^^^^^^");
+        assert_eq!(crate::error::error_category(e.as_ref()), "function application");
+    }
+}
@@ -1,14 +1,11 @@
 
-mod walkers;
-mod edit;
-mod commands;
-
 use std::error::Error;
 
 use clap::Parser;
 use clap::Subcommand;
+use clap::Args;
 
-use commands::*;
+use nix_codemod::cli::*;
 
 #[derive(Parser)]
 struct Cli {
@@ -16,6 +13,39 @@ struct Cli {
     command: Command
 }
 
+// What the edit commands do with the edited file, printed by default; not a
+// doc comment, since clap would take it for the help of the commands
+#[derive(Args)]
+struct OutputArgs {
+    /// Rewrite the file instead
+    #[clap(long)]
+    write: bool,
+    /// With `--write`, keep the original file as `<file>.orig`
+    #[clap(long, requires = "write")]
+    backup: bool,
+    /// Exit with an error if the file would change, without writing it
+    #[clap(long, conflicts_with = "write")]
+    check: bool,
+    /// Print a unified diff, with paths relative to `--nixpkgs` if given, or else to the
+    /// current directory
+    #[clap(long, conflicts_with_all = &["write", "check"])]
+    diff: bool,
+}
+
+impl OutputArgs {
+    fn output(&self) -> EditOutput {
+        if self.write {
+            EditOutput::Write { backup: self.backup }
+        } else if self.check {
+            EditOutput::Check
+        } else if self.diff {
+            EditOutput::Diff
+        } else {
+            EditOutput::Print
+        }
+    }
+}
+
 #[derive(Subcommand)]
 enum Command {
     ListSystemdServices {
@@ -26,6 +56,11 @@ enum Command {
     PrintSystemdServiceConfig {
         module: String,
         service: String,
+        #[clap(long)]
+        catalog: Option<String>,
+        /// Only report the options the systemd of this nixpkgs checkout has
+        #[clap(long)]
+        nixpkgs: Option<String>,
         #[clap(short, long)]
         verbose: bool,
     },
@@ -33,6 +68,10 @@ enum Command {
         module: String,
         service: String,
         options: String,
+        #[clap(long)]
+        catalog: Option<String>,
+        #[clap(flatten)]
+        output: OutputArgs,
         #[clap(short, long)]
         verbose: bool,
     },
@@ -40,6 +79,49 @@ enum Command {
         module: String,
         service: String,
         option_names: String,
+        #[clap(long)]
+        catalog: Option<String>,
+        /// Only hook the options the systemd of this nixpkgs checkout has
+        #[clap(long)]
+        nixpkgs: Option<String>,
+        #[clap(flatten)]
+        output: OutputArgs,
+    },
+    /// Hooks every service of the output of `collect-tests`
+    HookModules {
+        nixpkgs: String,
+        collected_tests: String,
+        #[clap(long)]
+        catalog: Option<String>,
+        #[clap(flatten)]
+        output: OutputArgs,
+    },
+    DiscoverSystemdServices {
+        nixpkgs: String,
+        output: String,
+        /// Where to write why some modules couldn't be reduced, instead of stderr
+        #[clap(long)]
+        failures: Option<String>,
+        /// Only look at the modules under this directory of `nixos/modules`, like `services`
+        #[clap(long = "prefix")]
+        prefixes: Vec<String>,
+        #[clap(long)]
+        catalog: Option<String>,
+        /// Defaults to `$XDG_CACHE_HOME/nix-codemod`
+        #[clap(long)]
+        cache_dir: Option<String>,
+        #[clap(long)]
+        no_cache: bool,
+    },
+    DiffDiscovery {
+        old: String,
+        new: String,
+        /// Defaults to `<old>.failures`, if it exists
+        #[clap(long)]
+        old_failures: Option<String>,
+        /// Defaults to `<new>.failures`, if it exists
+        #[clap(long)]
+        new_failures: Option<String>,
     },
     FindAllTests {
         all_tests: String,
@@ -47,24 +129,45 @@ enum Command {
     IsTestWellFormed {
         test: String,
     },
+    /// Answers JSON requests on stdin, one per line
+    Serve,
+}
+
+fn main() {
+    // errors are printed with `Display`, since located ones span several lines
+    if let Err(e) = run(Cli::parse()) {
+        eprintln!("error: {}", e);
+        std::process::exit(1);
+    }
 }
 
-fn main() -> Result<(), Box<dyn Error>> {
-    let cli = Cli::parse();
+fn run(cli: Cli) -> Result<(), Box<dyn Error>> {
 
     match cli.command {
         Command::ListSystemdServices { module, verbose } =>
             list_systemd_services(&module, verbose)?,
-        Command::PrintSystemdServiceConfig { module, service, verbose } =>
-            print_systemd_service_config(&module, &service, verbose)?,
-        Command::EditSystemdService { module, service, options, verbose } =>
-            edit_systemd_service(&module, &service, &options, verbose)?,
-        Command::InsertSystemdHooks { module, service, option_names } =>
-            insert_systemd_hooks(&module, &service, &option_names)?,
+        Command::PrintSystemdServiceConfig { module, service, catalog, nixpkgs, verbose } =>
+            print_systemd_service_config(&module, &service, catalog.as_deref(), nixpkgs.as_deref(), verbose)?,
+        Command::EditSystemdService { module, service, options, catalog, output, verbose } =>
+            edit_systemd_service(&module, &service, &options, catalog.as_deref(), &output.output(), verbose)?,
+        Command::InsertSystemdHooks { module, service, option_names, catalog, nixpkgs, output } =>
+            insert_systemd_hooks(
+                &module, &service, &option_names,
+                catalog.as_deref(), nixpkgs.as_deref(), &output.output())?,
+        Command::HookModules { nixpkgs, collected_tests, catalog, output } =>
+            hook_modules(&nixpkgs, &collected_tests, catalog.as_deref(), &output.output())?,
+        Command::DiscoverSystemdServices { nixpkgs, output, failures, prefixes, catalog, cache_dir, no_cache } =>
+            discover_systemd_services(
+                &nixpkgs, &output, failures.as_deref(), &prefixes,
+                catalog.as_deref(), cache_dir.as_deref(), no_cache)?,
+        Command::DiffDiscovery { old, new, old_failures, new_failures } =>
+            diff_discovery(&old, &new, old_failures.as_deref(), new_failures.as_deref())?,
         Command::FindAllTests { all_tests } =>
             find_all_tests(&all_tests)?,
         Command::IsTestWellFormed { test } =>
             is_test_well_formed(&test)?,
+        Command::Serve =>
+            serve()?,
     }
 
     Ok(())
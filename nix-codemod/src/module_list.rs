@@ -0,0 +1,106 @@
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::error::Error;
+
+use rnix::types::*;
+
+static MODULE_LIST: &str = "nixos/modules/module-list.nix";
+
+/// Every relative path in `module-list.nix`, in order: list items, but also
+/// `(import ./x.nix)`, `lib.optional cond ./y.nix`, both branches of an `if`...
+pub fn parse_module_list(content: &str) -> Result<Vec<String>, Box<dyn Error>> {
+    let ast = rnix::parse(content).as_result()?;
+    let mut paths: Vec<String> = vec!();
+
+    for n in ast.node().descendants() {
+        let v = match Value::cast(n).map(|v| v.to_value()) {
+            Some(Ok(v)) => v,
+            _ => continue,
+        };
+        if let rnix::value::Value::Path(rnix::value::Anchor::Relative, p) = v {
+            if !paths.contains(&p) {
+                paths.push(p);
+            }
+        }
+    }
+
+    Ok(paths)
+}
+
+/// `path` relative to `dir`, or its `default.nix` if it's a directory
+pub fn resolve(dir: &Path, path: &str) -> PathBuf {
+    let mut resolved = dir.to_path_buf();
+    for component in path.split('/') {
+        match component {
+            "" | "." => (),
+            ".." => { resolved.pop(); },
+            c => resolved.push(c),
+        }
+    }
+    if resolved.is_dir() {
+        resolved.push("default.nix");
+    }
+    resolved
+}
+
+/// The modules of a nixpkgs checkout, optionally only those under one of
+/// `prefixes` (like `services` or `security`, relative to `nixos/modules`)
+pub fn module_list(nixpkgs: &str, prefixes: &[String]) -> Result<Vec<PathBuf>, Box<dyn Error>> {
+    let path = Path::new(nixpkgs).join(MODULE_LIST);
+    let content = fs::read_to_string(&path)
+        .map_err(|e| format!("{}: {}", path.display(), e))?;
+    let dir = path.parent().ok_or("module-list.nix has no parent directory")?;
+
+    Ok(parse_module_list(&content)?.into_iter()
+        .filter(|p| {
+            let p = p.trim_start_matches("./");
+            prefixes.is_empty() || prefixes.iter().any(|prefix| {
+                p.strip_prefix(prefix.trim_start_matches("./").trim_end_matches('/'))
+                    .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+            })
+        })
+        .map(|p| resolve(dir, &p))
+        .collect())
+}
+
+#[cfg(test)]
+mod module_list_tests {
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+
+    #[test]
+    fn test_parse_module_list() {
+        let paths = parse_module_list(r#"
+        [
+          ./config/users-groups.nix
+          ./security/polkit.nix
+          ./services/web-servers/nginx
+          (import ./services/misc/legacy.nix)
+          ./system/boot/systemd.nix
+          ./virtualisation/docker.nix
+        ] ++ lib.optional (!pkgs.stdenv.isAarch64) ./hardware/cpu/intel-microcode.nix
+          ++ (if true then [ ./services/a.nix ] else [ ./services/b.nix ./security/polkit.nix ])
+        "#).unwrap();
+
+        assert_eq!(paths, vec![
+            "./config/users-groups.nix",
+            "./security/polkit.nix",
+            "./services/web-servers/nginx",
+            "./services/misc/legacy.nix",
+            "./system/boot/systemd.nix",
+            "./virtualisation/docker.nix",
+            "./hardware/cpu/intel-microcode.nix",
+            "./services/a.nix",
+            "./services/b.nix",
+        ]);
+    }
+
+    #[test]
+    fn test_resolve() {
+        let dir = Path::new("/nonexistent/nixos/modules");
+        assert_eq!(resolve(dir, "./services/a.nix"), dir.join("services/a.nix"));
+        assert_eq!(resolve(dir, "./services/../security/b.nix"), dir.join("security/b.nix"));
+    }
+}
@@ -0,0 +1,75 @@
+
+use std::fs;
+use std::path::Path;
+use std::error::Error;
+
+use rnix::types::*;
+use rnix::SyntaxNode;
+
+use crate::walkers::*;
+
+static SYSTEMD_PACKAGE: &str = "pkgs/os-specific/linux/systemd/default.nix";
+
+/// Finds the first `version = "..."` binding, wherever it is (`let`, `rec { }`
+/// or the attribute set given to `mkDerivation`)
+fn find_version(root: &SyntaxNode) -> Option<String> {
+    root.descendants()
+        .filter_map(KeyValue::cast)
+        .filter(|kv| {
+            kv.key().map(|k| k.path().collect::<Vec<_>>())
+                .is_some_and(|path| path.len() == 1 && matches!(parse_ident(path[0].clone()), Ok(Some(name)) if name == "version"))
+        })
+        .find_map(|kv| kv.value().as_ref().and_then(static_string))
+}
+
+/// Reads the major version of the systemd packaged in `content`
+pub fn parse_systemd_version(content: &str) -> Result<u32, Box<dyn Error>> {
+    let ast = rnix::parse(content).as_result()?;
+    let version = find_version(&ast.node()).ok_or("couldn't find systemd's version")?;
+    let major: String = version.chars().take_while(char::is_ascii_digit).collect();
+    major.parse().map_err(|_| format!("unexpected systemd version \"{}\"", version).into())
+}
+
+/// The major version of the systemd packaged in the given nixpkgs checkout
+pub fn systemd_version(nixpkgs: &str) -> Result<u32, Box<dyn Error>> {
+    let path = Path::new(nixpkgs).join(SYSTEMD_PACKAGE);
+    let content = fs::read_to_string(&path)
+        .map_err(|e| format!("{}: {}", path.display(), e))?;
+    parse_systemd_version(&content)
+}
+
+#[cfg(test)]
+mod nixpkgs_tests {
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+
+    #[test]
+    fn test_let_version() {
+        assert_eq!(parse_systemd_version(r#"
+        { stdenv, lib, fetchFromGitHub, withHomed ? false }:
+        let
+          version = "247.6";
+        in stdenv.mkDerivation {
+          inherit version;
+          pname = "systemd";
+        }
+        "#).unwrap(), 247);
+    }
+
+    #[test]
+    fn test_final_attrs_version() {
+        assert_eq!(parse_systemd_version(r#"
+        { stdenv, lib, fetchFromGitHub }:
+        stdenv.mkDerivation (finalAttrs: {
+          pname = "systemd";
+          version = "254.6";
+          src = fetchFromGitHub {
+            owner = "systemd";
+            repo = "systemd-stable";
+            rev = "v${finalAttrs.version}";
+          };
+        })
+        "#).unwrap(), 254);
+    }
+}
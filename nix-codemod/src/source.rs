@@ -0,0 +1,90 @@
+
+use std::fs;
+use std::error::Error;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use crate::location::in_file;
+
+struct Parsed {
+    modified: SystemTime,
+    len: u64,
+    content: String,
+    ast: rnix::AST,
+}
+
+thread_local! {
+    /// The files parsed on this thread, once `keep_parsed_files` was called
+    static PARSED: RefCell<Option<HashMap<PathBuf, Parsed>>> = const { RefCell::new(None) };
+}
+
+/// From now on, `parse_file` keeps the files it parses on this thread, until
+/// they change on disk. Only `serve` does: a batch over nixpkgs would keep
+/// all of it in memory.
+pub fn keep_parsed_files() {
+    PARSED.with(|parsed| {
+        parsed.borrow_mut().get_or_insert_with(HashMap::new);
+    });
+}
+
+/// The content of `file` and its syntax tree, with syntax errors located in it
+pub fn parse_file(file: &Path) -> Result<(String, rnix::AST), Box<dyn Error>> {
+    let metadata = fs::metadata(file).map_err(|e| in_file(file, "", e.into()))?;
+    let modified = metadata.modified().ok();
+
+    let cached = PARSED.with(|parsed| {
+        let parsed = parsed.borrow();
+        let p = parsed.as_ref()?.get(file)?;
+        if Some(p.modified) == modified && p.len == metadata.len() {
+            Some((p.content.clone(), p.ast.clone()))
+        } else {
+            None
+        }
+    });
+    if let Some(cached) = cached {
+        return Ok(cached)
+    }
+
+    let content = fs::read_to_string(file).map_err(|e| in_file(file, "", e.into()))?;
+    let ast = rnix::parse(&content).as_result().map_err(|e| in_file(file, &content, e.into()))?;
+
+    PARSED.with(|parsed| {
+        if let (Some(parsed), Some(modified)) = (parsed.borrow_mut().as_mut(), modified) {
+            let p = Parsed { modified, len: metadata.len(), content: content.clone(), ast: ast.clone() };
+            parsed.insert(file.to_path_buf(), p);
+        }
+    });
+
+    Ok((content, ast))
+}
+
+#[cfg(test)]
+mod source_tests {
+    use pretty_assertions::assert_eq;
+
+    use crate::test_dir::TestDir;
+    use super::*;
+
+    #[test]
+    fn test_parse_file() {
+        let dir = TestDir::new("source");
+        let file = dir.path().join("module.nix");
+
+        keep_parsed_files();
+        fs::write(&file, "{ }").unwrap();
+        assert_eq!(parse_file(&file).unwrap().0, "{ }");
+        assert_eq!(parse_file(&file).unwrap().0, "{ }");
+        fs::write(&file, "{ a = 1; }").unwrap();
+        assert_eq!(parse_file(&file).unwrap().0, "{ a = 1; }");
+
+        fs::write(&file, "{ a = ; }").unwrap();
+        let e = parse_file(&file).err().unwrap();
+        assert_eq!(crate::error::error_category(e.as_ref()), "syntax");
+
+        fs::remove_file(&file).unwrap();
+        let e = parse_file(&file).err().unwrap();
+        assert_eq!(crate::error::error_category(e.as_ref()), "io");
+    }
+}
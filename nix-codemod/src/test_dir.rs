@@ -0,0 +1,38 @@
+//! A temporary directory for the tests that read and write files
+
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// A directory in the system's temporary directory, removed with its content
+/// when dropped, so also when an assertion fails
+pub struct TestDir(PathBuf);
+
+impl TestDir {
+    /// An empty directory; `name` tells apart the tests of the process
+    pub fn new(name: &str) -> TestDir {
+        let dir = env::temp_dir().join(format!("nix-codemod-{}-test-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        TestDir(dir)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+
+    /// Writes `content` to `file`, relative to the directory, creating the
+    /// directories it's in
+    pub fn write(&self, file: &str, content: &str) -> PathBuf {
+        let path = self.0.join(file);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(&path, content).unwrap();
+        path
+    }
+}
+
+impl Drop for TestDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
@@ -4,8 +4,12 @@ use std::error::Error;
 use rnix::types::*;
 use rnix::value;
 use rnix::SyntaxNode;
+use rnix::SyntaxKind;
 use rnix::StrPart;
 
+use crate::error::*;
+use crate::location::{Position, position};
+
 pub fn expect_relative_path(n: SyntaxNode) -> Result<String, Box<dyn Error>> {
     let v = Value::cast(n)
         .ok_or("unexpected file structure")?
@@ -26,49 +30,173 @@ pub fn parse_ident(n: SyntaxNode) -> Result<Option<String>, Box<dyn Error>> {
     }
 }
 
-fn extract_simple_string(n: Str) -> Option<String> {
-    if n.parts().len() == 1 {
-        if let Some(StrPart::Literal(s)) = n.parts().first() {
-            Some(s.clone())
-        } else {
-            panic!("Isn't that case unreachable?")
-        }
-    } else {
-        None
-    }
+/// A piece of a string with interpolations
+#[derive(Clone, Debug, PartialEq)]
+pub enum StrFragment {
+    Literal(String),
+    /// The source text of an interpolated expression, without `${` and `}`
+    Interpolation(String),
 }
 
+#[derive(Clone, Debug, PartialEq)]
 pub enum CfgValue {
     Str(String),
     Bool(bool),
-    List(Vec<String>),
+    Int(i64),
+    /// A path, as written in the source
+    Path(String),
+    Null,
+    /// A string with interpolations, like `"${pkg}/bin/x"`
+    Interpolated(Vec<StrFragment>),
+    List(Vec<CfgValue>),
+    AttrSet(Vec<(String, CfgValue)>),
+    /// A value wrapped in `mkDefault`, `mkForce` or `mkOverride`
+    Prioritized {
+        priority: i64,
+        inner: Box<CfgValue>,
+    },
     NotReduced,
 }
 
+/// The priority of `mkDefault`
+pub const DEFAULT_PRIORITY: i64 = 1000;
+/// The priority of `mkForce`
+pub const FORCE_PRIORITY: i64 = 50;
+
+impl std::fmt::Display for CfgValue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CfgValue::Str(s) => write!(f, "{}", quote_string(s)),
+            CfgValue::Bool(b) => write!(f, "{}", b),
+            CfgValue::Int(i) => write!(f, "{}", i),
+            CfgValue::Path(p) => write!(f, "{}", p),
+            CfgValue::Null => write!(f, "null"),
+            CfgValue::Interpolated(fragments) => {
+                write!(f, "\"")?;
+                for fragment in fragments {
+                    match fragment {
+                        StrFragment::Literal(s) => {
+                            let quoted = quote_string(s);
+                            write!(f, "{}", &quoted[1..quoted.len() - 1])?
+                        },
+                        StrFragment::Interpolation(s) => write!(f, "${{{}}}", s)?,
+                    }
+                }
+                write!(f, "\"")
+            },
+            CfgValue::List(elems) => {
+                write!(f, "[ ")?;
+                for elem in elems {
+                    write!(f, "{} ", elem)?;
+                }
+                write!(f, "]")
+            },
+            CfgValue::AttrSet(entries) => {
+                write!(f, "{{ ")?;
+                for (key, value) in entries {
+                    write!(f, "{} = {}; ", key, value)?;
+                }
+                write!(f, "}}")
+            },
+            CfgValue::Prioritized { priority, inner } => {
+                match *priority {
+                    DEFAULT_PRIORITY => write!(f, "mkDefault ")?,
+                    FORCE_PRIORITY => write!(f, "mkForce ")?,
+                    priority => write!(f, "mkOverride {} ", priority)?,
+                }
+                match **inner {
+                    CfgValue::Prioritized { .. } => write!(f, "({})", inner),
+                    _ => write!(f, "{}", inner),
+                }
+            },
+            CfgValue::NotReduced => write!(f, "<not reduced>"),
+        }
+    }
+}
+
 pub fn parse_cfg_value(n: SyntaxNode) -> Result<CfgValue, Box<dyn Error>> {
-    match ParsedType::try_from(n)? {
-        ParsedType::Str(n) => {
-            if let Some(s) = extract_simple_string(n) {
-                Ok(CfgValue::Str(s.clone()))
+    cfg_value(n, 0)
+}
+
+/// `parse_cfg_value`, having followed `depth` bindings to get to `n`
+fn cfg_value(n: SyntaxNode, depth: usize) -> Result<CfgValue, Box<dyn Error>> {
+    if let Some((c, mut args)) = parse_combinator(&n)? {
+        let priority = match c.name {
+            "mkDefault" => Some(DEFAULT_PRIORITY),
+            "mkForce" => Some(FORCE_PRIORITY),
+            "mkOverride" => match cfg_value(args[0].clone(), depth)? {
+                CfgValue::Int(priority) => Some(priority),
+                _ => return Ok(CfgValue::NotReduced),
+            },
+            _ => None,
+        };
+
+        return match priority {
+            Some(priority) => Ok(CfgValue::Prioritized {
+                priority,
+                inner: Box::new(cfg_value(args.swap_remove(c.value_arg), depth)?),
+            }),
+            None => Ok(CfgValue::NotReduced),
+        }
+    }
+
+    if let Some(entries) = attrset_entries(n.clone())? {
+        if entries.iter().any(DeclKV::is_unreduced) {
+            return Ok(CfgValue::NotReduced)
+        }
+        return Ok(CfgValue::AttrSet(entries.into_iter()
+            .map(|kv| Ok((key_to_string(&kv.key), cfg_value(kv.value, depth)?)))
+            .collect::<Result<_, Box<dyn Error>>>()?))
+    }
+
+    match ParsedType::try_from(n.clone())? {
+        ParsedType::Str(s) => {
+            let fragments = s.parts().into_iter()
+                .map(|part| match part {
+                    StrPart::Literal(s) => StrFragment::Literal(s),
+                    StrPart::Ast(interpol) => StrFragment::Interpolation(
+                        interpol.first_child().map(|n| n.to_string()).unwrap_or_default()),
+                })
+                .collect::<Vec<_>>();
+
+            if let Some(s) = static_string(&n) {
+                Ok(CfgValue::Str(s))
             } else {
-                Ok(CfgValue::NotReduced)
+                Ok(CfgValue::Interpolated(fragments))
             }
         },
-        ParsedType::Ident(n) => {
-            match n.as_str() {
+        ParsedType::Value(v) => {
+            match v.to_value()? {
+                value::Value::Integer(i) => Ok(CfgValue::Int(i)),
+                value::Value::Path(..) => Ok(CfgValue::Path(n.to_string())),
+                value::Value::String(s) => Ok(CfgValue::Str(s)),
+                value::Value::Float(_) => Ok(CfgValue::NotReduced),
+            }
+        },
+        ParsedType::UnaryOp(op) if op.operator() == UnaryOpKind::Negate => {
+            match cfg_value(op.value().ok_or_else(|| parse_error(op.node()))?, depth)? {
+                CfgValue::Int(i) => Ok(CfgValue::Int(-i)),
+                _ => Ok(CfgValue::NotReduced),
+            }
+        },
+        ParsedType::Ident(i) => {
+            match i.as_str() {
                 "true" => Ok(CfgValue::Bool(true)),
                 "false" => Ok(CfgValue::Bool(false)),
-                _ => Ok(CfgValue::NotReduced)
+                "null" => Ok(CfgValue::Null),
+                // `let a = a; in` would loop forever
+                _ if depth >= MAX_RESOLVE_DEPTH => Err(CodemodError::TooDeep(i.node().text_range()))?,
+                _ => match resolve_ident(&i) {
+                    Some(v) => cfg_value(v, depth + 1),
+                    None => Ok(CfgValue::NotReduced),
+                },
             }
         },
-        ParsedType::List(n) => {
-            let elems = n.items()
-                .map(|n| extract_simple_string(Str::cast(n)?))
-                .collect::<Option<Vec<String>>>();
-            match elems {
-                Some(elems) => Ok(CfgValue::List(elems)),
-                None => Ok(CfgValue::NotReduced)
-            }
+        ParsedType::Paren(p) => cfg_value(p.inner().ok_or_else(|| parse_error(p.node()))?, depth),
+        ParsedType::List(l) => {
+            Ok(CfgValue::List(l.items()
+                .map(|item| cfg_value(item, depth))
+                .collect::<Result<_, _>>()?))
         },
         _ => Ok(CfgValue::NotReduced)
     }
@@ -88,11 +216,11 @@ pub fn parse_ident_select(n: SyntaxNode) -> Result<Vec<String>, Box<dyn Error>>
                 },
                 ParsedType::Select(n) => {
                     self.idents.push(
-                        parse_ident(n.index().ok_or("parse error")?)?
+                        parse_ident(n.index().ok_or_else(|| parse_error(n.node()))?)?
                         .ok_or("expected an identifier")?);
-                    self.visit(n.set().ok_or("parse error")?)
+                    self.visit(n.set().ok_or_else(|| parse_error(n.node()))?)
                 },
-                _ => Err("parse error")?
+                n => Err(unsupported(n.node()))?
             }
         }
     }
@@ -104,40 +232,367 @@ pub fn parse_ident_select(n: SyntaxNode) -> Result<Vec<String>, Box<dyn Error>>
     Ok(v.idents)
 }
 
+/// A function of nixpkgs' `lib` that wraps a module value.
+pub struct Combinator {
+    pub name: &'static str,
+    /// The `lib` attribute set it is also exported from, as in `lib.modules.mkIf`
+    pub namespace: &'static str,
+    /// The number of arguments it takes
+    pub arity: usize,
+    /// The position of the argument that holds the value
+    pub value_arg: usize,
+}
+
+impl Combinator {
+    /// Whether `path` names this function, either bare, as `lib.name`
+    /// or as `lib.namespace.name`.
+    pub fn matches(&self, path: &[String]) -> bool {
+        match path {
+            [name] => name == self.name,
+            [lib, name] => lib == "lib" && name == self.name,
+            [lib, namespace, name] => lib == "lib" && namespace == self.namespace && name == self.name,
+            _ => false,
+        }
+    }
+}
+
+pub static COMBINATORS: &[Combinator] = &[
+    Combinator { name: "mkIf", namespace: "modules", arity: 2, value_arg: 1 },
+    Combinator { name: "mkMerge", namespace: "modules", arity: 1, value_arg: 0 },
+    Combinator { name: "mkDefault", namespace: "modules", arity: 1, value_arg: 0 },
+    Combinator { name: "mkForce", namespace: "modules", arity: 1, value_arg: 0 },
+    Combinator { name: "mkOverride", namespace: "modules", arity: 2, value_arg: 1 },
+    Combinator { name: "mkBefore", namespace: "modules", arity: 1, value_arg: 0 },
+    Combinator { name: "mkAfter", namespace: "modules", arity: 1, value_arg: 0 },
+    Combinator { name: "optionalAttrs", namespace: "attrsets", arity: 2, value_arg: 1 },
+];
+
+/// One of the `COMBINATORS` along with the arguments it is applied to
+pub type CombinatorApp = (&'static Combinator, Vec<SyntaxNode>);
+
+/// If `n` is a full application of one of the `COMBINATORS`, returns it
+/// along with its arguments.
+pub fn parse_combinator(n: &SyntaxNode) -> Result<Option<CombinatorApp>, Box<dyn Error>> {
+    let mut args = vec!();
+    let mut f = n.clone();
+    while let Some(app) = Apply::cast(f.clone()) {
+        args.push(app.value().ok_or_else(|| parse_error(app.node()))?);
+        f = app.lambda().ok_or_else(|| parse_error(app.node()))?;
+    }
+    args.reverse();
+
+    let path = match parse_ident_select(f) {
+        Ok(path) => path,
+        Err(_) => return Ok(None),
+    };
+
+    Ok(COMBINATORS.iter()
+        .find(|c| c.arity == args.len() && c.matches(&path))
+        .map(|c| (c, args)))
+}
+
 pub fn go_right_value(n: SyntaxNode) -> Result<SyntaxNode, Box<dyn Error>> {
-    match ParsedType::try_from(n) {
+    match ParsedType::try_from(n.clone()) {
         Ok(ParsedType::LetIn(n)) => {
-            go_right_value(n.body().ok_or("parse error")?)
+            go_right_value(n.body().ok_or_else(|| parse_error(n.node()))?)
         },
         Ok(ParsedType::With(n)) => {
-            go_right_value(n.body().ok_or("parse error")?)
+            go_right_value(n.body().ok_or_else(|| parse_error(n.node()))?)
+        },
+        Ok(ParsedType::Paren(n)) => {
+            go_right_value(n.inner().ok_or_else(|| parse_error(n.node()))?)
         },
         Ok(ParsedType::Apply(n)) => {
-            if let Ok(p) = parse_ident_select(n.lambda().ok_or("parse error")?) {
-                if p == ["mkMerge"] {
-                    todo!("handle mkMerge")
-                } else {
-                    Ok(n.node().clone())
-                }
-            } else {
-                match ParsedType::try_from(n.lambda().ok_or("parse error")?)? {
-                    ParsedType::Apply(n1) => {
-                        if let Ok(p) = parse_ident_select(n1.lambda().ok_or("parse error")?) {
-                            if p == ["mkIf"] {
-                                n.value().ok_or("parse error".into())
-                            } else {
-                                Ok(n.node().clone())
-                            }
-                        } else {
-                            Ok(n.node().clone())
-                        }
-                    },
-                    _ => Ok(n.node().clone())
-                }
+            match parse_combinator(n.node())? {
+                // `mkMerge [ ... ]` stays as is, `decl_value` looks into
+                // every element of the list.
+                Some((c, _)) if c.name == "mkMerge" => Ok(n.node().clone()),
+                Some((c, mut args)) => go_right_value(args.swap_remove(c.value_arg)),
+                None => Ok(n.node().clone()),
             }
         },
         Ok(n) => Ok(n.node().clone()),
-        _ => Err("parse error")?
+        _ => Err(parse_error(&n))?
+    }
+}
+
+/// If `n` is `mkMerge [ ... ]`, returns the elements of the list.
+pub fn merge_elements(n: &SyntaxNode) -> Result<Option<Vec<SyntaxNode>>, Box<dyn Error>> {
+    match parse_combinator(n)? {
+        Some((c, mut args)) if c.name == "mkMerge" => {
+            let list = go_right_value(args.swap_remove(c.value_arg))?;
+            let list = List::cast(list.clone()).ok_or(CodemodError::MkMerge(list.text_range()))?;
+            Ok(Some(list.items().collect()))
+        },
+        _ => Ok(None),
+    }
+}
+
+/// How deep the walkers follow bindings before giving up, so that
+/// `let a = a; in` doesn't loop forever.
+const MAX_RESOLVE_DEPTH: usize = 32;
+
+/// Finds the node of `name` in the attribute set `set`, if it is declared
+/// there with a simple `name = ...;` entry.
+pub fn select_attr(set: &SyntaxNode, name: &str) -> Option<SyntaxNode> {
+    select_attr_entries(AttrSet::cast(set.clone())?.entries(), name)
+}
+
+fn binding_in(
+    name: &str,
+    scope: &SyntaxNode,
+    entries: impl Iterator<Item = KeyValue>,
+    mut inherits: impl Iterator<Item = Inherit>
+) -> Option<Option<SyntaxNode>> {
+    if let Some(value) = select_attr_entries(entries, name) {
+        return Some(Some(value))
+    }
+
+    let inherit = inherits.find(|inherit| inherit.idents().any(|i| i.as_str() == name))?;
+    Some(match inherit.from() {
+        // `inherit (from) name;`
+        Some(from) => {
+            let from = resolve_value(from.inner()?).ok()?;
+            select_attr(&from, name)
+        },
+        // `inherit name;` binds the `name` of the enclosing scope
+        None => lookup_binding(name, &scope.parent()?),
+    })
+}
+
+fn select_attr_entries(mut entries: impl Iterator<Item = KeyValue>, name: &str) -> Option<SyntaxNode> {
+    entries
+        .find(|entry| match entry.key() {
+            Some(key) => {
+                let path: Vec<_> = key.path().collect();
+                path.len() == 1 && Ident::cast(path[0].clone()).is_some_and(|i| i.as_str() == name)
+            },
+            None => false,
+        })
+        .and_then(|entry| entry.value())
+}
+
+/// Finds the value `name` is bound to by a `let ... in` or a `rec { ... }`
+/// around `n`. Returns `None` if it isn't bound there, or if a function
+/// argument shadows it.
+pub fn lookup_binding(name: &str, n: &SyntaxNode) -> Option<SyntaxNode> {
+    for scope in n.ancestors() {
+        match ParsedType::try_from(scope.clone()) {
+            Ok(ParsedType::LetIn(s)) => {
+                if let Some(v) = binding_in(name, &scope, s.entries(), s.inherits()) {
+                    return v
+                }
+            },
+            Ok(ParsedType::AttrSet(s)) if s.recursive() => {
+                if let Some(v) = binding_in(name, &scope, s.entries(), s.inherits()) {
+                    return v
+                }
+            },
+            Ok(ParsedType::Lambda(l)) => {
+                let shadowed = match l.arg().map(ParsedType::try_from) {
+                    Some(Ok(ParsedType::Ident(i))) => i.as_str() == name,
+                    Some(Ok(ParsedType::Pattern(p))) =>
+                        p.entries().filter_map(|e| e.name()).any(|i| i.as_str() == name)
+                        || p.at().is_some_and(|i| i.as_str() == name),
+                    _ => false,
+                };
+                if shadowed {
+                    return None
+                }
+            },
+            _ => (),
+        }
+    }
+
+    None
+}
+
+/// Finds the value the identifier `i` refers to. This also handles the
+/// identifiers of `inherit` statements, that refer to the scope around
+/// the attribute set, or to the attribute set they inherit from.
+pub fn resolve_ident(i: &Ident) -> Option<SyntaxNode> {
+    if let Some(inherit) = i.node().parent().and_then(Inherit::cast) {
+        return match inherit.from() {
+            Some(from) => select_attr(&resolve_value(from.inner()?).ok()?, i.as_str()),
+            None => lookup_binding(i.as_str(), &inherit.node().parent()?.parent()?),
+        }
+    }
+
+    lookup_binding(i.as_str(), i.node())
+}
+
+/// Follows parentheses, wrappers and identifiers bound in the enclosing
+/// scopes to find the expression that defines the value of `n`.
+pub fn resolve_value(n: SyntaxNode) -> Result<SyntaxNode, Box<dyn Error>> {
+    let range = n.text_range();
+    let mut n = go_right_value(n)?;
+    for _ in 0..MAX_RESOLVE_DEPTH {
+        match Ident::cast(n.clone()).and_then(|i| resolve_ident(&i)) {
+            Some(v) => n = go_right_value(v)?,
+            None => return Ok(n),
+        }
+    }
+    Err(CodemodError::TooDeep(range))?
+}
+
+/// Counts the identifiers of the file that refer to `target` (through
+/// any number of bindings and selected attributes).
+pub fn count_references(target: &SyntaxNode) -> usize {
+    let root = target.ancestors().last().unwrap_or_else(|| target.clone());
+    root.descendants()
+        .filter_map(Ident::cast)
+        .filter(|i| match i.node().parent() {
+            // the names of bindings and of selected attributes aren't
+            // references, and `inherit (from)` only selects the inherited ones
+            Some(parent) => match parent.kind() {
+                SyntaxKind::NODE_KEY | SyntaxKind::NODE_PAT_ENTRY | SyntaxKind::NODE_PAT_BIND => false,
+                SyntaxKind::NODE_INHERIT_FROM => false,
+                SyntaxKind::NODE_LAMBDA => parent.first_child().as_ref() != Some(i.node()),
+                SyntaxKind::NODE_SELECT => parent.first_child().as_ref() == Some(i.node()),
+                _ => true,
+            },
+            None => false,
+        })
+        .filter(|i| refers_to(i, target))
+        .count()
+}
+
+/// Whether the identifier `i` may refer to `target`: if its value or an
+/// attribute selected from it is `target`, or, in doubt, contains it
+fn refers_to(i: &Ident, target: &SyntaxNode) -> bool {
+    let contains = |n: &SyntaxNode| n.text_range().contains_range(target.text_range());
+    let mut n = i.node().clone();
+    let mut value = match resolve_value(n.clone()) {
+        Ok(value) => value,
+        Err(_) => return false,
+    };
+
+    loop {
+        if &value == target {
+            return true
+        }
+        if !contains(&value) {
+            return false
+        }
+
+        // `value.attr`; when the whole value is used, or the attribute
+        // can't be found, `target` may be used through it
+        let select = match n.parent().and_then(Select::cast) {
+            Some(select) if select.set().as_ref() == Some(&n) => select,
+            _ => return true,
+        };
+        let attr = select.index().and_then(|index| match Ident::cast(index.clone()) {
+            Some(i) => Some(i.as_str().to_string()),
+            None => static_string(&index),
+        });
+        value = match attr.and_then(|attr| select_attr(&value, &attr)).map(resolve_value) {
+            Some(Ok(value)) => value,
+            _ => return true,
+        };
+        n = select.node().clone();
+    }
+}
+
+/// Evaluates `n` to a string, if it is a string literal that only
+/// interpolates let-bound constants.
+pub fn static_string(n: &SyntaxNode) -> Option<String> {
+    fn go(n: &SyntaxNode, depth: usize) -> Option<String> {
+        if depth > MAX_RESOLVE_DEPTH {
+            return None
+        }
+
+        match ParsedType::try_from(n.clone()).ok()? {
+            ParsedType::Str(s) => s.parts().into_iter()
+                .map(|part| match part {
+                    StrPart::Literal(s) => Some(s),
+                    StrPart::Ast(interpol) => go(&interpol.first_child()?, depth + 1),
+                })
+                .collect(),
+            ParsedType::Ident(i) => go(&resolve_ident(&i)?, depth + 1),
+            ParsedType::Paren(p) => go(&p.inner()?, depth + 1),
+            _ => None,
+        }
+    }
+
+    go(n, 0)
+}
+
+/// One component of an attribute path
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub enum KeyPart {
+    Static(String),
+    /// A name only known at evaluation time, like `"${cfg.name}-worker"`
+    /// or `${name}`, or the names of an operand of `//` we can't reduce,
+    /// with its source text
+    Dynamic(String),
+}
+
+impl KeyPart {
+    pub fn as_static(&self) -> Option<&str> {
+        match self {
+            KeyPart::Static(s) => Some(s),
+            KeyPart::Dynamic(_) => None,
+        }
+    }
+
+    /// Whether this is the static name `name`
+    pub fn is(&self, name: &str) -> bool {
+        self.as_static() == Some(name)
+    }
+}
+
+/// Writes `s` as a nix string literal
+pub fn quote_string(s: &str) -> String {
+    format!("\"{}\"", s
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace("${", "\\${")
+        .replace('\n', "\\n")
+        .replace('\r', "\\r")
+        .replace('\t', "\\t"))
+}
+
+/// Quotes `name` if it can't be written as a bare attribute name
+pub fn quote_attr(name: &str) -> String {
+    let is_ident = name.chars().next().is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-' || c == '\'');
+    if is_ident {
+        name.to_string()
+    } else {
+        quote_string(name)
+    }
+}
+
+impl std::fmt::Display for KeyPart {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            KeyPart::Static(s) => write!(f, "{}", quote_attr(s)),
+            KeyPart::Dynamic(s) => write!(f, "{}", s),
+        }
+    }
+}
+
+/// Formats an attribute path the way it would be written in nix
+pub fn key_to_string(key: &[KeyPart]) -> String {
+    key.iter().map(|k| k.to_string()).collect::<Vec<_>>().join(".")
+}
+
+/// The name one part of an attribute path stands for: `a`, `"a"` and
+/// `${"a"}` are all `a`
+pub fn parse_key_part(n: SyntaxNode) -> Result<KeyPart, Box<dyn Error>> {
+    match ParsedType::try_from(n.clone())? {
+        ParsedType::Ident(n) => {
+            Ok(KeyPart::Static(n.as_str().to_string()))
+        },
+        ParsedType::Str(_) => {
+            Ok(static_string(&n).map(KeyPart::Static)
+                .unwrap_or_else(|| KeyPart::Dynamic(n.to_string())))
+        },
+        ParsedType::Dynamic(d) => {
+            Ok(d.inner().and_then(|inner| static_string(&inner)).map(KeyPart::Static)
+                .unwrap_or_else(|| KeyPart::Dynamic(n.to_string())))
+        },
+        _ => Err(parse_error(&n))?
     }
 }
 
@@ -146,102 +601,327 @@ pub struct DeclKV {
     /// The KeyValue node that declared this entry
     pub node: SyntaxNode,
     /// The name of the node in the attribute set it originates from 
-    pub key: Vec<String>,
+    pub key: Vec<KeyPart>,
     pub value: SyntaxNode,
 }
 
+/// If `n` is a chain of `//`, returns its operands from left to right,
+/// without their parentheses.
+pub fn update_operands(n: &SyntaxNode) -> Option<Vec<SyntaxNode>> {
+    fn go(n: SyntaxNode, operands: &mut Vec<SyntaxNode>) {
+        let n = match Paren::cast(n.clone()).and_then(|p| p.inner()) {
+            Some(inner) => inner,
+            None => n,
+        };
+
+        match BinOp::cast(n.clone()) {
+            Some(op) if op.operator() == Some(BinOpKind::Update) => {
+                if let (Some(lhs), Some(rhs)) = (op.lhs(), op.rhs()) {
+                    go(lhs, operands);
+                    go(rhs, operands);
+                }
+            },
+            _ => operands.push(n),
+        }
+    }
+
+    let op = BinOp::cast(n.clone())?;
+    if op.operator() != Some(BinOpKind::Update) {
+        return None
+    }
+
+    let mut operands = vec!();
+    go(n.clone(), &mut operands);
+    Some(operands)
+}
+
+/// Whether `n` evaluates to an attribute set whose entries we know, none
+/// of them named after one of `names`
+fn is_reduced_set_without(n: &SyntaxNode, names: &[String]) -> bool {
+    match resolve_value(n.clone()).map(attrset_entries) {
+        Ok(Ok(Some(entries))) => !entries.iter().any(|e| match e.key.first().and_then(KeyPart::as_static) {
+            Some(name) => names.iter().any(|n| n == name),
+            None => true,
+        }),
+        _ => false,
+    }
+}
+
+/// The attribute set the new entries `names` of `n` should be written to:
+/// `n` itself if it is an attribute set, or the right-most attribute set
+/// literal of a chain of `//`. There is none if an operand on its right
+/// may declare one of `names`, since it would override them.
+pub fn update_target(n: &SyntaxNode, names: &[String]) -> Option<SyntaxNode> {
+    match update_operands(n) {
+        Some(operands) => operands.into_iter().rev()
+            .take_while(|n| AttrSet::cast(n.clone()).is_some() || is_reduced_set_without(n, names))
+            .find(|n| AttrSet::cast(n.clone()).is_some()),
+        None => AttrSet::cast(n.clone()).map(|n| n.node().clone()),
+    }
+}
+
+impl DeclKV {
+    /// Whether this entry comes from an `inherit` statement, in which case
+    /// `node` is the `Inherit` node and `value` the inherited identifier.
+    pub fn is_inherited(&self) -> bool {
+        self.node.kind() == SyntaxKind::NODE_INHERIT
+    }
+
+    /// Whether this entry stands for an operand of `//` we can't reduce,
+    /// which may declare any name: `node` and `value` are the operand, and
+    /// `key` its source text, as a dynamic name.
+    pub fn is_unreduced(&self) -> bool {
+        self.node == self.value
+    }
+
+    /// The line and column where the entry is declared; the inherited
+    /// identifier's, since one `inherit` can declare several entries
+    pub fn position(&self) -> Position {
+        if self.is_inherited() { position(&self.value) } else { position(&self.node) }
+    }
+}
+
 pub fn attrset_entries(n: SyntaxNode) -> Result<Option<Vec<DeclKV>>, Box<dyn Error>> {
     match ParsedType::try_from(n)? {
-        ParsedType::AttrSet(n) => n.entries()
-            .map(|entry| {
-                let keys = entry.key().ok_or("parse error")?.path().map(|n| -> Result<String, Box<dyn Error>> {
-                    match ParsedType::try_from(n)? {
-                        ParsedType::Ident(n) => {
-                            Ok(n.as_str().to_string())
-                        },
-                        ParsedType::Str(n) => {
-                            if n.parts().len() == 1 {
-                                if let Some(StrPart::Literal(s)) = n.parts().first() {
-                                    Ok(s.clone())
-                                } else {
-                                    panic!("Isn't that case unreachable?")
-                                }
-                            } else {
-                                todo!()
-                            }
-                        },
-                        _ => Err("Unexpected node type when unrolling keys")?
-                    }
-                }).collect::<Result<Vec<String>, _>>()?;
-                Ok(DeclKV {
-                    node: entry.node().clone(),
-                    key: keys,
-                    value: entry.value().ok_or("parse error")?,
+        ParsedType::AttrSet(n) => {
+            let mut entries = n.entries()
+                .map(|entry| {
+                    let keys = entry.key().ok_or_else(|| parse_error(entry.node()))?.path()
+                        .map(parse_key_part)
+                        .collect::<Result<Vec<KeyPart>, _>>()?;
+                    Ok(DeclKV {
+                        node: entry.node().clone(),
+                        key: keys,
+                        value: entry.value().ok_or_else(|| parse_error(entry.node()))?,
+                    })
                 })
-            })
-            .collect::<Result<Vec<DeclKV>, _>>().map(Some),
+                .collect::<Result<Vec<DeclKV>, Box<dyn Error>>>()?;
+
+            entries.extend(n.inherits()
+                .flat_map(|inherit| inherit.idents()
+                    .map(move |ident| DeclKV {
+                        node: inherit.node().clone(),
+                        key: vec![KeyPart::Static(ident.as_str().to_string())],
+                        value: ident.node().clone(),
+                    })));
+
+            Ok(Some(entries))
+        },
+        ParsedType::BinOp(op) if op.operator() == Some(BinOpKind::Update) => {
+            // `//` is shallow: an entry hides the ones of the operands on
+            // its left that start with the same name. The operands we can't
+            // reduce are kept as entries of their own, since they may
+            // declare or hide anything.
+            let mut defined: Vec<KeyPart> = vec!();
+            let mut operands_entries = vec!();
+
+            for operand in update_operands(op.node()).unwrap_or_default().into_iter().rev() {
+                let entries = match attrset_entries(resolve_value(operand.clone())?)? {
+                    Some(entries) => entries,
+                    None => vec![DeclKV {
+                        node: operand.clone(),
+                        key: vec![KeyPart::Dynamic(operand.to_string())],
+                        value: operand,
+                    }],
+                };
+
+                let names: Vec<KeyPart> = entries.iter().map(|e| e.key[0].clone()).collect();
+                operands_entries.push(entries.into_iter()
+                    .filter(|e| !defined.contains(&e.key[0]))
+                    .collect::<Vec<_>>());
+                defined.extend(names);
+            }
+
+            operands_entries.reverse();
+            Ok(Some(operands_entries.concat()))
+        },
         _ => Ok(None)
     }
 }
 
+pub type DeclEntries = Vec<(Vec<KeyPart>, DeclKV)>;
+
 #[derive(Clone)]
 pub enum DeclValue {
     Node(DeclKV),
     PartialAttr {
         node: SyntaxNode,
         prefix: Vec<String>,
-        entries: Vec<(Vec<String>, DeclKV)>
+        entries: DeclEntries,
     },
+    /// The same value, declared in several elements of a `mkMerge`, or
+    /// both as a whole set and entry by entry in the same set.
+    /// Never empty, never a singleton and never nested.
+    Merged(Vec<DeclValue>),
 }
 
 impl DeclValue {
+    /// Builds the value declared by all of `decls`.
+    pub fn merged(decls: Vec<DeclValue>) -> Option<DeclValue> {
+        let mut decls: Vec<DeclValue> = decls.into_iter()
+            .flat_map(|decl| match decl {
+                DeclValue::Merged(decls) => decls,
+                decl => vec![decl],
+            })
+            .collect();
+
+        match decls.len() {
+            0 => None,
+            1 => decls.pop(),
+            _ => Some(DeclValue::Merged(decls)),
+        }
+    }
+
+    /// The declaration new entries should be added to: for a `Merged`
+    /// value, the first element no `mkIf` guards, preferably one that
+    /// declares a whole attribute set, or the first element if all of them
+    /// are guarded.
+    pub fn primary(&self) -> &DeclValue {
+        match self {
+            DeclValue::Merged(decls) => {
+                let unguarded = || decls.iter().filter(|decl| !decl.is_guarded());
+                unguarded().find(|decl| matches!(decl, DeclValue::Node(_)))
+                    .or_else(|| unguarded().next())
+                    .or_else(|| decls.iter().find(|decl| matches!(decl, DeclValue::Node(_))))
+                    .unwrap_or(&decls[0])
+            },
+            decl => decl,
+        }
+    }
+
+    /// Whether a `mkIf` or an `optionalAttrs` applies to the value, between
+    /// its declaration and the list of the `mkMerge` it's an element of
+    pub fn is_guarded(&self) -> bool {
+        let n = match self {
+            DeclValue::Node(kv) => &kv.value,
+            DeclValue::Merged(_) => return self.primary().is_guarded(),
+            decl => decl.decl_node(),
+        };
+        n.ancestors()
+            .take_while(|n| n.kind() != SyntaxKind::NODE_LIST)
+            .any(|n| matches!(parse_combinator(&n), Ok(Some((c, _))) if c.name == "mkIf" || c.name == "optionalAttrs"))
+    }
+
     pub fn value(&self) -> &SyntaxNode {
         match self {
             DeclValue::Node(kv) => &kv.value,
-            DeclValue::PartialAttr { node, .. } => &node,
+            DeclValue::PartialAttr { node, .. } => node,
+            DeclValue::Merged(_) => self.primary().value(),
         }
     }
 
+    /// The declaration of the value, or of its first entry for a partial
+    /// attribute set
+    fn decl_node(&self) -> &SyntaxNode {
+        match self {
+            DeclValue::Node(kv) if kv.is_inherited() => &kv.value,
+            DeclValue::Node(kv) => &kv.node,
+            DeclValue::PartialAttr { node, entries, .. } =>
+                entries.first().map(|(_, kv)| &kv.node).unwrap_or(node),
+            DeclValue::Merged(_) => self.primary().decl_node(),
+        }
+    }
+
+    /// The line and column where the value is declared
+    pub fn position(&self) -> Position {
+        position(self.decl_node())
+    }
+
     pub fn prefix(&self) -> &[String] {
         match self {
             DeclValue::Node(_) => &[],
-            DeclValue::PartialAttr { prefix, .. } => &prefix,
+            DeclValue::PartialAttr { prefix, .. } => prefix,
+            DeclValue::Merged(_) => self.primary().prefix(),
         }
     }
 
-    pub fn entries(self) -> Result<Option<Vec<(Vec<String>, DeclKV)>>, Box<dyn Error>> {
+    pub fn entries(self) -> Result<Option<DeclEntries>, Box<dyn Error>> {
         match self {
-            DeclValue::Node(n) => Ok(attrset_entries(n.value)?
-                .map(|entries| entries.into_iter().map(|entry| (entry.key.clone(), entry)).collect())),
+            DeclValue::Node(n) => Ok(value_entries(n.value, 0)?.ok()),
             DeclValue::PartialAttr { entries, .. } => Ok(Some(entries)),
+            DeclValue::Merged(decls) => {
+                let mut all = vec![];
+                for decl in decls {
+                    match decl.entries()? {
+                        Some(mut entries) => all.append(&mut entries),
+                        None => return Ok(None),
+                    }
+                }
+                Ok(Some(all))
+            },
+        }
+    }
+
+    /// Like `entries`, but tells why they can't be reduced
+    pub fn reduce_entries(self) -> Result<DeclEntries, Box<dyn Error>> {
+        match self {
+            DeclValue::Node(n) => Ok(value_entries(n.value, 0)?.map_err(|n| unsupported(&n))?),
+            DeclValue::PartialAttr { entries, .. } => Ok(entries),
+            DeclValue::Merged(decls) => {
+                let mut all = vec![];
+                for decl in decls {
+                    all.append(&mut decl.reduce_entries()?);
+                }
+                Ok(all)
+            },
         }
     }
 
     pub fn project(self, p: &str) -> Result<Option<DeclValue>, Box<dyn Error>> {
         match self {
             DeclValue::Node(n) => {
-                let n = go_right_value(n.value)?;
+                let n = resolve_value(n.value)?;
                 decl_value(&[p.to_string()], n)
             },
             DeclValue::PartialAttr { node, mut prefix, entries } => {
-                let mut v: Vec<_> = entries.into_iter()
-                    .filter(|(attr_name, _)| if let Some(q) = attr_name.first() { p == q } else { false })
+                let v: Vec<_> = entries.into_iter()
+                    .filter(|(attr_name, _)| if let Some(q) = attr_name.first() { q.is(p) } else { false })
                     .map(|(mut attr_name, DeclKV { node, key, value })| {
                         attr_name.remove(0);
                         (attr_name, DeclKV { node, key, value })
                     }).collect();
-                if v.is_empty() {
-                    Ok(None)
-                } else if v.len() == 1 && v.first().unwrap().0.len() == 0 {
-                    let (_, kv) = v.remove(0);
-                    Ok(Some(DeclValue::Node(kv)))
-                } else {
+                // `p = { ... };` next to `p.<key> = ...;`: Nix merges both
+                // sets, so does a `Merged` value
+                let (whole, v): (Vec<_>, Vec<_>) = v.into_iter().partition(|(rest, _)| rest.is_empty());
+                let mut decls: Vec<DeclValue> = whole.into_iter().map(|(_, kv)| DeclValue::Node(kv)).collect();
+                if !v.is_empty() {
                     prefix.push(p.to_string());
-                    Ok(Some(DeclValue::PartialAttr { node, prefix, entries: v }))
+                    decls.push(DeclValue::PartialAttr { node, prefix, entries: v });
                 }
+                Ok(DeclValue::merged(decls))
+            },
+            DeclValue::Merged(decls) => {
+                let decls = decls.into_iter()
+                    .map(|decl| decl.project(p))
+                    .collect::<Result<Vec<_>, _>>()?;
+                Ok(DeclValue::merged(decls.into_iter().flatten().collect()))
+            },
+        }
+    }
+}
+
+/// The entries of the attribute set `n` evaluates to, or of all the elements
+/// of a `mkMerge`; otherwise, the value that isn't an attribute set
+fn value_entries(n: SyntaxNode, depth: usize) -> Result<Result<DeclEntries, SyntaxNode>, Box<dyn Error>> {
+    let n = resolve_value(n)?;
+
+    if let Some(elems) = merge_elements(&n)? {
+        if depth > MAX_RESOLVE_DEPTH {
+            Err(CodemodError::TooDeep(n.text_range()))?
+        }
+        let mut all = vec![];
+        for elem in elems {
+            match value_entries(elem, depth + 1)? {
+                Ok(mut entries) => all.append(&mut entries),
+                Err(n) => return Ok(Err(n)),
             }
         }
+        return Ok(Ok(all))
     }
+
+    Ok(attrset_entries(n.clone())?
+        .map(|entries| entries.into_iter().map(|entry| (entry.key.clone(), entry)).collect())
+        .ok_or(n))
 }
 
 pub fn decl_value(path: &[String], n: SyntaxNode) -> Result<Option<DeclValue>, Box<dyn Error>> {
@@ -257,24 +937,41 @@ pub fn decl_value(path: &[String], n: SyntaxNode) -> Result<Option<DeclValue>, B
                     DeclValue::PartialAttr { entries: mut entries2, .. }) => {
                     entries.append(&mut entries2);
                 },
-                _ => Err("defined multiple times")?,
+                (_, v) => Err(CodemodError::DefinedMultipleTimes(v.value().text_range()))?,
             }
             Ok(())
         }
     }
 
+    if let Some(elems) = merge_elements(&n)? {
+        let mut decls = vec![];
+        for elem in elems {
+            if let Some(v) = decl_value(path, go_right_value(elem)?)? {
+                decls.push(v)
+            }
+        }
+        return Ok(DeclValue::merged(decls))
+    }
+
     let mut holder = ValHolder { val: None };
 
-    for kv in attrset_entries(n.clone())?.ok_or("couldn't reduce")? {
+    for kv in attrset_entries(n.clone())?.ok_or_else(|| unsupported(&n))? {
         // Check the paths match
-        if !Iterator::zip(path.iter(), kv.key.iter()).all(|(p, q)| p == q) {
+        if let Some(i) = Iterator::zip(path.iter(), kv.key.iter()).position(|(p, q)| !q.is(p)) {
+            // `a.${x}.c` may well be the `a.b.c` we look for
+            if i + 1 < path.len() && kv.is_unreduced() {
+                Err(unsupported(&kv.node))?
+            }
+            if i + 1 < path.len() && kv.key[i].as_static().is_none() {
+                Err(CodemodError::DynamicKey(kv.node.text_range()))?
+            }
             continue
         }
 
         match Ord::cmp(&path.len(), &kv.key.len()) {
             std::cmp::Ordering::Less => {
                 holder.merge(DeclValue::PartialAttr {
-                    node: n.clone(),
+                    node: update_target(&n, &path[..1]).unwrap_or_else(|| n.clone()),
                     prefix: path.into(),
                     entries: vec![(
                         kv.key[path.len()..].into(),
@@ -286,7 +983,7 @@ pub fn decl_value(path: &[String], n: SyntaxNode) -> Result<Option<DeclValue>, B
                 holder.merge(DeclValue::Node(kv))?
             },
             std::cmp::Ordering::Greater => {
-                let x = go_right_value(kv.value)?;
+                let x = resolve_value(kv.value)?;
                 let x = decl_value(&path[kv.key.len()..], x)?;
                 if let Some(x) = x { holder.merge(x)? }
             },
@@ -296,3 +993,25 @@ pub fn decl_value(path: &[String], n: SyntaxNode) -> Result<Option<DeclValue>, B
     Ok(holder.val)
 }
 
+/// The body of a module's function
+pub fn root_body(root: &Root) -> Result<SyntaxNode, Box<dyn Error>> {
+    let x = root.inner().and_then(Lambda::cast)
+        .ok_or_else(|| CodemodError::NonLambdaRoot(root.node().text_range()))?;
+    go_right_value(x.body().ok_or_else(|| parse_error(x.node()))?)
+}
+
+/// Where a module declares `config.systemd.services.<service>`
+/// The declaration of `config.systemd.services.<service>`, if there is one
+pub fn service_decl(root: Root, service: &str) -> Result<Option<DeclValue>, Box<dyn Error>> {
+    let x = root_body(&root)?;
+    decl_value(
+        &[  "config".to_string(),
+            "systemd".to_string(),
+            "services".to_string(),
+            service.to_string()],
+        x)
+}
+
+pub fn find_service_decl(root: Root, service: &str) -> Result<DeclValue, Box<dyn Error>> {
+    service_decl(root, service)?.ok_or(format!("config.systemd.services.{} is not declared", service).into())
+}
@@ -45,9 +45,13 @@ in rec {
           then go acc path val
           else acc) acc (lib.attrNames x);
     in builtins.listToAttrs (go [] "." x);
+  # The least restrictive value of each option, so that a hooked service
+  # behaves as before.
+  optionDefaults = builtins.listToAttrs (map (opt: lib.nameValuePair opt.name (builtins.head opt.values))
+    (builtins.fromJSON (builtins.readFile ./nix-codemod/catalog.json)).options);
   mkSystemdPassthru = collectedTests:
     let tests = builtins.fromJSON (builtins.readFile collectedTests);
-    in lib.mapAttrs (_: value: builtins.listToAttrs (map (name: lib.nameValuePair name false) value.fields)) tests;
+    in lib.mapAttrs (_: value: builtins.listToAttrs (map (name: lib.nameValuePair name (optionDefaults.${name} or false)) value.fields)) tests;
   mkOverrideOptions = defaultPassthru: service: override:
     defaultPassthru // { "${service}" = defaultPassthru."${service}" // override; };
   mkHookedTests = nixpkgs: systemdPassthru: